@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    process::{Command, Output, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use macro_deck_driver::MacroDeck;
+use serde_json::Value;
+use ureq::{http::Request, Agent};
+
+use super::dispatch::Job;
+use super::models::ButtonAction;
+use super::profile::switch_profile;
+use super::sequence::run_sequence;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for the output of a command once it exited
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+
+/// Describe the output of a finished command, failing on a non-zero exit.
+fn describe_output(command: &str, output: &Output) -> Result<String, String> {
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", command, output.status));
+    }
+
+    Ok(format!(
+        "Command output: {}",
+        if stdout.is_empty() { "None" } else { &stdout }
+    ))
+}
+
+/// Run a command to the end, with its exit code. The command is killed when
+/// the job has to stop.
+fn run_command(
+    mut command: Command,
+    name: &str,
+    job: &Job,
+) -> (Result<String, String>, Option<i32>) {
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return (Err(format!("Failed to execute {}: {}", name, e)), None),
+    };
+
+    // Read the output while waiting, the command blocks on a full pipe
+    let mut stdout = child.stdout.take().unwrap();
+    let (output_tx, output_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut chunk = [0; 4096];
+        while let Ok(len @ 1..) = stdout.read(&mut chunk) {
+            if output_tx.send(chunk[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let status = job.wait_for(|| {
+        child
+            .try_wait()
+            .map_err(|e| format!("Failed to wait for {}: {}", name, e))
+    });
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return (Err(format!("Killed {}: {}", name, e)), None);
+        }
+    };
+
+    // Programs started in the background may keep the output open, the
+    // reader is left to them with what was printed so far
+    let until = Instant::now() + OUTPUT_GRACE;
+    let mut stdout = vec![];
+    while let Ok(chunk) = output_rx.recv_timeout(until.saturating_duration_since(Instant::now())) {
+        stdout.extend(chunk);
+    }
+    let output = Output {
+        status,
+        stdout,
+        stderr: vec![],
+    };
+    (describe_output(name, &output), status.code())
+}
+
+#[cfg(windows)]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(windows)]
+fn url_opener(url: &str) -> Command {
+    let mut opener = Command::new("cmd");
+    opener.args(["/C", "start", "", url]);
+    opener
+}
+
+#[cfg(target_os = "macos")]
+fn url_opener(url: &str) -> Command {
+    let mut opener = Command::new("open");
+    opener.arg(url);
+    opener
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn url_opener(url: &str) -> Command {
+    let mut opener = Command::new("xdg-open");
+    opener.arg(url);
+    opener
+}
+
+fn http_request(
+    method: &str,
+    url: &str,
+    body: Option<&Value>,
+    headers: &HashMap<String, String>,
+    job: &Job,
+) -> Result<String, String> {
+    job.check()?;
+    let timeout = job
+        .remaining()
+        .map_or(HTTP_TIMEOUT, |remaining| remaining.min(HTTP_TIMEOUT));
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .build()
+        .into();
+
+    let mut builder = Request::builder().method(method).uri(url);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+
+    let result = match body {
+        None => builder.body(()).map(|request| agent.run(request)),
+        Some(Value::String(body)) => builder.body(body.clone()).map(|request| agent.run(request)),
+        Some(body) => {
+            if !headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case("content-type"))
+            {
+                builder = builder.header("Content-Type", "application/json");
+            }
+            builder
+                .body(body.to_string())
+                .map(|request| agent.run(request))
+        }
+    };
+
+    let mut response = result
+        .map_err(|e| format!("Invalid request to {}: {}", url, e))?
+        .map_err(|e| format!("{} {} failed: {}", method, url, e))?;
+    let status = response.status();
+    let text = response.body_mut().read_to_string().unwrap_or_default();
+
+    Ok(format!("{} {}: {} {}", method, url, status, text.trim()))
+}
+
+/// Run the action of the button `key` and describe what it did.
+pub fn run_action(
+    deck: &MacroDeck,
+    state_dir: &Path,
+    key: &str,
+    action: &ButtonAction,
+    job: &Job,
+) -> Result<String, String> {
+    execute(deck, state_dir, key, action, job).0
+}
+
+/// Run an action, with the exit code of a command
+pub fn execute(
+    deck: &MacroDeck,
+    state_dir: &Path,
+    key: &str,
+    action: &ButtonAction,
+    job: &Job,
+) -> (Result<String, String>, Option<i32>) {
+    let result = match action {
+        ButtonAction::Exec { command, args } => {
+            let mut program = Command::new(command);
+            program.args(args.iter().flatten());
+            return run_command(program, command, job);
+        }
+        ButtonAction::Shell { command } => return run_command(shell(command), command, job),
+        ButtonAction::OpenUrl { url } => run_command(url_opener(url), url, job)
+            .0
+            .map(|_| format!("Opened {}", url)),
+        ButtonAction::HttpRequest {
+            method,
+            url,
+            body,
+            headers,
+        } => http_request(
+            &method.as_deref().unwrap_or("GET").to_uppercase(),
+            url,
+            body.as_ref(),
+            headers,
+            job,
+        ),
+        ButtonAction::TcpSend { address, data } => TcpStream::connect(address)
+            .and_then(|mut stream| stream.write_all(data.as_bytes()))
+            .map(|()| format!("Sent {} bytes to {}", data.len(), address))
+            .map_err(|e| format!("Failed to send to {}: {}", address, e)),
+        ButtonAction::WriteFile {
+            path,
+            content,
+            append,
+        } => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(*append)
+            .truncate(!append)
+            .open(path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .map(|()| format!("Wrote {}", path))
+            .map_err(|e| format!("Failed to write {}: {}", path, e)),
+        ButtonAction::SwitchProfile { profile } => switch_profile(deck, state_dir, profile)
+            .map(|()| format!("Switched to profile {}", profile)),
+        ButtonAction::Sequence { steps } => run_sequence(deck, state_dir, key, steps, job),
+    };
+
+    (result, None)
+}
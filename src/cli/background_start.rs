@@ -57,16 +57,13 @@ pub fn background_start(
     #[cfg(unix)]
     {
         use nix::unistd::setsid;
-        use std::{
-            io::{Error, ErrorKind},
-            os::unix::process::CommandExt,
-        };
+        use std::{io::Error, os::unix::process::CommandExt};
 
         unsafe {
             if command
                 .pre_exec(|| match setsid() {
                     Ok(_) => Ok(()),
-                    Err(e) => Err(Error::new(ErrorKind::Other, e)),
+                    Err(e) => Err(Error::other(e)),
                 })
                 .spawn()
                 .is_err()
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose, Engine as _};
+use macro_deck_driver::{DeviceInfo, NodeKind};
+use serde::{Deserialize, Serialize};
+
+use super::fs::Device;
+use super::models::{FsEntry, FsRequest};
+
+const MANIFEST: &str = "manifest.json";
+/// Version of the manifest written, and the only one restored
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Geometry {
+    width: u32,
+    height: u32,
+    buttons_per_row: u32,
+    num_of_rows: u32,
+    gap_size: u32,
+}
+
+impl From<&DeviceInfo> for Geometry {
+    fn from(info: &DeviceInfo) -> Self {
+        Geometry {
+            width: info.width,
+            height: info.height,
+            buttons_per_row: info.buttons_per_row,
+            num_of_rows: info.num_of_rows,
+            gap_size: info.gap_size,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BackupFile {
+    /// Path on the device
+    path: String,
+    /// Path of the file in the archive, holding it as stored on the device
+    archive: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BackupManifest {
+    version: u32,
+    /// Seconds since the Unix epoch
+    created: u64,
+    device: Geometry,
+    /// Profile shown on the device, if the driver switched to one
+    profile: Option<String>,
+    folders: Vec<String>,
+    files: Vec<BackupFile>,
+}
+
+fn create_backup(device: &Device, out: &str) -> Result<usize, String> {
+    let info = device.info()?;
+    let entries: Vec<FsEntry> = serde_json::from_value(device.run(FsRequest::Ls {
+        path: "/".to_string(),
+        recursive: true,
+    })?)
+    .map_err(|_| "Invalid directory listing".to_string())?;
+
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let mut builder = tar::Builder::new(
+        File::create(out).map_err(|e| format!("Failed to create {}: {}", out, e))?,
+    );
+    let mut append = |name: &str, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(created);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, data)
+            .map_err(|e| format!("Failed to write {}: {}", out, e))
+    };
+
+    let mut manifest = BackupManifest {
+        version: VERSION,
+        created,
+        device: Geometry::from(&info),
+        profile: device.active_profile()?,
+        folders: vec![],
+        files: vec![],
+    };
+
+    for FsEntry {
+        path: entry, kind, ..
+    } in entries
+    {
+        if kind == NodeKind::Folder {
+            manifest.folders.push(entry);
+            continue;
+        }
+
+        let data = device
+            .run(FsRequest::Pull {
+                path: entry.clone(),
+            })?
+            .as_str()
+            .and_then(|encoded| general_purpose::STANDARD.decode(encoded).ok())
+            .ok_or(format!("Failed to read {}", entry))?;
+
+        let archive = format!("files/{}", entry.trim_start_matches('/'));
+        append(&archive, &data)?;
+        println!("Saved {}", entry);
+
+        manifest.files.push(BackupFile {
+            path: entry,
+            archive,
+        });
+    }
+
+    append(
+        MANIFEST,
+        serde_json::to_string_pretty(&manifest).unwrap().as_bytes(),
+    )?;
+    builder
+        .finish()
+        .map_err(|e| format!("Failed to write {}: {}", out, e))?;
+
+    Ok(manifest.files.len())
+}
+
+/// Save every folder and file of the device to a tar archive.
+pub fn backup(tcp_port: Option<String>, port: Option<String>, out: Option<String>) {
+    let out = out.unwrap_or("deck.tar".to_string());
+
+    let result = Device::connect(tcp_port, port).and_then(|device| create_backup(&device, &out));
+    match result {
+        Ok(count) => println!("Wrote {} files to {}", count, out),
+        Err(e) => {
+            eprintln!("Backup failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_archive(path: &str) -> Result<HashMap<String, Vec<u8>>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let mut archive = tar::Archive::new(file);
+
+    let mut entries = HashMap::new();
+    for entry in archive
+        .entries()
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?
+    {
+        let mut entry = entry.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let name = entry
+            .path()
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?
+            .display()
+            .to_string();
+
+        let mut data = vec![];
+        entry
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        entries.insert(name, data);
+    }
+
+    Ok(entries)
+}
+
+fn restore_backup(device: &Device, archive: &str) -> Result<usize, String> {
+    let mut entries = read_archive(archive)?;
+    let manifest: BackupManifest = entries
+        .get(MANIFEST)
+        .and_then(|data| serde_json::from_slice(data).ok())
+        .ok_or(format!("{} is not a device backup", archive))?;
+    if manifest.version != VERSION {
+        return Err(format!(
+            "{} has backup version {}, only version {} is supported",
+            archive, manifest.version, VERSION
+        ));
+    }
+
+    let geometry = Geometry::from(&device.info()?);
+    if geometry != manifest.device {
+        return Err(format!(
+            "The backup is for a {}x{} device with {}x{} buttons and a gap of {}, this device is {}x{} with {}x{} buttons and a gap of {}",
+            manifest.device.width,
+            manifest.device.height,
+            manifest.device.buttons_per_row,
+            manifest.device.num_of_rows,
+            manifest.device.gap_size,
+            geometry.width,
+            geometry.height,
+            geometry.buttons_per_row,
+            geometry.num_of_rows,
+            geometry.gap_size,
+        ));
+    }
+
+    // Check the archive before touching the device
+    for file in &manifest.files {
+        if !entries.contains_key(&file.archive) {
+            return Err(format!("{} is missing from the backup", file.archive));
+        }
+    }
+
+    device.run(FsRequest::Rm {
+        path: "/".to_string(),
+        recursive: true,
+    })?;
+
+    let mut folders = manifest.folders.clone();
+    // Parents sort before their children
+    folders.sort();
+    for folder in folders {
+        device.run(FsRequest::Mkdir { path: folder })?;
+    }
+
+    for file in &manifest.files {
+        let data = entries.remove(&file.archive).unwrap_or_default();
+        device.run(FsRequest::Push {
+            path: file.path.clone(),
+            image: general_purpose::STANDARD.encode(data),
+        })?;
+        println!("Restored {}", file.path);
+    }
+
+    if let Some(profile) = &manifest.profile {
+        device.switch_profile(profile)?;
+        println!("Switched to profile {}", profile);
+    }
+
+    Ok(manifest.files.len())
+}
+
+/// Replace everything on the device with the content of a backup.
+pub fn restore(tcp_port: Option<String>, port: Option<String>, archive: String) {
+    let result =
+        Device::connect(tcp_port, port).and_then(|device| restore_backup(&device, &archive));
+    match result {
+        Ok(count) => println!("Restored {} files from {}", count, archive),
+        Err(e) => {
+            eprintln!("Restore failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
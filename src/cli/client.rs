@@ -0,0 +1,32 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use serde_json::Value;
+
+use super::models::Message;
+
+/// Send a message to the driver listening on `tcp_port` and return the value
+/// of its reply. A reply with an `error` field fails with that error.
+pub fn request(tcp_port: &str, type_: &str, value: Option<Value>) -> Result<Value, String> {
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", tcp_port))
+        .map_err(|_| format!("Failed to connect to TCP port: {}", tcp_port))?;
+
+    let msg = Message {
+        type_: type_.to_string(),
+        value,
+    };
+    writeln!(stream, "{}", serde_json::to_string(&msg).unwrap())
+        .map_err(|_| "Failed to send message".to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|_| "Failed to read response".to_string())?;
+
+    let msg: Message = serde_json::from_str(&line).map_err(|_| "Invalid response".to_string())?;
+    let value = msg.value.unwrap_or_default();
+    match value.get("error").and_then(|error| error.as_str()) {
+        Some(error) => Err(error.to_string()),
+        None => Ok(value),
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use super::models::Concurrency;
+
+pub const DEFAULT_WORKERS: usize = 4;
+/// How often a running handler checks whether it has to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// One run of a handler, which stops when cancelled or past its timeout
+pub struct Job {
+    cancelled: AtomicBool,
+    timeout: Option<Duration>,
+    /// Set by the worker, waiting for one does not count towards the timeout
+    started: OnceLock<Instant>,
+}
+
+impl Job {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Job {
+            cancelled: AtomicBool::new(false),
+            timeout,
+            started: OnceLock::new(),
+        }
+    }
+
+    fn start(&self) {
+        let _ = self.started.set(Instant::now());
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started
+            .get()
+            .map_or(Duration::ZERO, |started| started.elapsed())
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Why the job has to stop, if it does
+    pub fn check(&self) -> Result<(), String> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err("Cancelled by a new press".to_string());
+        }
+        match self.timeout {
+            Some(timeout) if self.elapsed() >= timeout => {
+                Err(format!("Timed out after {} ms", timeout.as_millis()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Time left before the timeout, `None` without one
+    pub fn remaining(&self) -> Option<Duration> {
+        self.timeout
+            .map(|timeout| timeout.saturating_sub(self.elapsed()))
+    }
+
+    /// Sleep unless the job has to stop first.
+    pub fn sleep(&self, duration: Duration) -> Result<(), String> {
+        let until = Instant::now() + duration;
+        loop {
+            self.check()?;
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            thread::sleep(left.min(POLL_INTERVAL));
+        }
+    }
+
+    /// Check the job every poll interval until `done` returns a value.
+    pub fn wait_for<T>(
+        &self,
+        mut done: impl FnMut() -> Result<Option<T>, String>,
+    ) -> Result<T, String> {
+        loop {
+            if let Some(value) = done()? {
+                return Ok(value);
+            }
+            self.check()?;
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&Job) -> Result<String, String> + Send + Sync>;
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Running jobs of a button and the presses waiting for them
+#[derive(Default)]
+struct Button {
+    jobs: Vec<Arc<Job>>,
+    pending: usize,
+}
+
+/// A press waiting for a worker
+struct Press {
+    key: String,
+    timeout: Option<Duration>,
+    handler: Handler,
+}
+
+/// Runs the button handlers on a pool of worker threads, so a slow handler
+/// does not hold up the serial reader thread or the other buttons.
+pub struct Dispatcher {
+    sender: mpsc::Sender<Task>,
+    buttons: Mutex<HashMap<String, Button>>,
+}
+
+impl Dispatcher {
+    pub fn start(workers: usize) -> Arc<Self> {
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // Only hold the lock while waiting, not while running
+                let task = receiver.lock().unwrap().recv();
+                match task {
+                    Ok(task) => task(),
+                    Err(_) => return,
+                }
+            });
+        }
+
+        Arc::new(Dispatcher {
+            sender,
+            buttons: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Handle a press of the button `key` according to its policy.
+    pub fn dispatch(
+        self: &Arc<Self>,
+        key: &str,
+        policy: Concurrency,
+        timeout: Option<Duration>,
+        handler: Handler,
+    ) {
+        let mut buttons = self.buttons.lock().unwrap();
+        let button = buttons.entry(key.to_string()).or_default();
+
+        if !button.jobs.is_empty() {
+            match policy {
+                Concurrency::Parallel => {}
+                Concurrency::Queue => {
+                    debug!("[{}] Queued behind the running handler", key);
+                    button.pending += 1;
+                    return;
+                }
+                Concurrency::IgnoreWhileRunning => {
+                    debug!("[{}] Ignored, the handler is still running", key);
+                    return;
+                }
+                Concurrency::Restart => {
+                    debug!("[{}] Restarting the handler", key);
+                    button.jobs.iter().for_each(|job| job.cancel());
+                    button.pending = 1;
+                    return;
+                }
+            }
+        }
+
+        let press = Press {
+            key: key.to_string(),
+            timeout,
+            handler,
+        };
+        self.run(button, press);
+    }
+
+    fn run(self: &Arc<Self>, button: &mut Button, press: Press) {
+        let job = Arc::new(Job::new(press.timeout));
+        button.jobs.push(job.clone());
+
+        let dispatcher = self.clone();
+        let task = Box::new(move || {
+            job.start();
+            match (press.handler)(&job) {
+                Ok(done) => debug!("[{}] {}", press.key, done),
+                Err(e) => warn!("[{}] {}", press.key, e),
+            }
+            dispatcher.finish(press, &job);
+        });
+        if self.sender.send(task).is_err() {
+            warn!("No worker left to run the handlers");
+        }
+    }
+
+    /// Forget the finished job and start a waiting press.
+    fn finish(self: &Arc<Self>, press: Press, job: &Arc<Job>) {
+        let mut buttons = self.buttons.lock().unwrap();
+        let button = match buttons.get_mut(&press.key) {
+            Some(button) => button,
+            None => return,
+        };
+
+        button.jobs.retain(|running| !Arc::ptr_eq(running, job));
+        if button.jobs.is_empty() && button.pending > 0 {
+            button.pending -= 1;
+            self.run(button, press);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Handlers which run until released, counting what they did
+    #[derive(Default)]
+    struct Probe {
+        released: AtomicBool,
+        runs: AtomicUsize,
+        running: AtomicUsize,
+        most_running: AtomicUsize,
+        cancelled: AtomicUsize,
+    }
+
+    impl Probe {
+        fn handler(self: &Arc<Self>, done: mpsc::Sender<()>) -> Handler {
+            let probe = self.clone();
+            let done = Mutex::new(done);
+            Arc::new(move |job: &Job| {
+                probe.runs.fetch_add(1, Ordering::SeqCst);
+                let running = probe.running.fetch_add(1, Ordering::SeqCst) + 1;
+                probe.most_running.fetch_max(running, Ordering::SeqCst);
+
+                let result =
+                    job.wait_for(|| Ok(probe.released.load(Ordering::SeqCst).then_some(())));
+                if result.is_err() {
+                    probe.cancelled.fetch_add(1, Ordering::SeqCst);
+                }
+
+                probe.running.fetch_sub(1, Ordering::SeqCst);
+                done.lock().unwrap().send(()).unwrap();
+                result.map(|()| "Released".to_string())
+            })
+        }
+
+        fn release(&self) {
+            self.released.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Press the button `presses` times, release the handlers and wait for
+    /// the runs to end.
+    fn press(policy: Concurrency, presses: usize, runs: usize) -> Arc<Probe> {
+        let dispatcher = Dispatcher::start(4);
+        let probe = Arc::new(Probe::default());
+        let (done_tx, done_rx) = mpsc::channel();
+
+        for _ in 0..presses {
+            dispatcher.dispatch("/default/0", policy, None, probe.handler(done_tx.clone()));
+        }
+        if policy == Concurrency::Restart {
+            // The first run has to see the cancel before anything is released
+            done_rx.recv_timeout(WAIT).unwrap();
+        }
+        probe.release();
+
+        let ended = if policy == Concurrency::Restart { 1 } else { 0 };
+        for _ in ended..runs {
+            done_rx.recv_timeout(WAIT).unwrap();
+        }
+        // Nothing else runs once the expected runs ended
+        assert!(done_rx.recv_timeout(POLL_INTERVAL * 4).is_err());
+        assert!(dispatcher.buttons.lock().unwrap()["/default/0"]
+            .jobs
+            .is_empty());
+        probe
+    }
+
+    #[test]
+    fn queue_runs_every_press_one_at_a_time() {
+        let probe = press(Concurrency::Queue, 3, 3);
+        assert_eq!(probe.runs.load(Ordering::SeqCst), 3);
+        assert_eq!(probe.most_running.load(Ordering::SeqCst), 1);
+        assert_eq!(probe.cancelled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn ignore_while_running_drops_the_presses() {
+        let probe = press(Concurrency::IgnoreWhileRunning, 3, 1);
+        assert_eq!(probe.runs.load(Ordering::SeqCst), 1);
+        assert_eq!(probe.cancelled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn restart_cancels_the_running_handler_and_runs_once_more() {
+        let probe = press(Concurrency::Restart, 3, 2);
+        assert_eq!(probe.runs.load(Ordering::SeqCst), 2);
+        assert_eq!(probe.most_running.load(Ordering::SeqCst), 1);
+        assert_eq!(probe.cancelled.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn parallel_runs_the_presses_together() {
+        let dispatcher = Dispatcher::start(2);
+        let probe = Arc::new(Probe::default());
+        let (done_tx, done_rx) = mpsc::channel();
+
+        for _ in 0..2 {
+            let handler = probe.handler(done_tx.clone());
+            dispatcher.dispatch("/default/0", Concurrency::Parallel, None, handler);
+        }
+        let started = Instant::now();
+        while probe.running.load(Ordering::SeqCst) < 2 && started.elapsed() < WAIT {
+            thread::sleep(POLL_INTERVAL);
+        }
+        probe.release();
+
+        for _ in 0..2 {
+            done_rx.recv_timeout(WAIT).unwrap();
+        }
+        assert_eq!(probe.most_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn timeout_stops_the_handler() {
+        let dispatcher = Dispatcher::start(1);
+        let probe = Arc::new(Probe::default());
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let handler = probe.handler(done_tx);
+        let timeout = Some(Duration::from_millis(100));
+        dispatcher.dispatch("/default/0", Concurrency::Queue, timeout, handler);
+
+        done_rx.recv_timeout(WAIT).unwrap();
+        assert_eq!(probe.cancelled.load(Ordering::SeqCst), 1);
+    }
+}
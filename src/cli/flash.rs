@@ -1,170 +1,1321 @@
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader};
 use std::net::TcpStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{collections::HashMap, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{Cursor, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
 
-use base64::engine::general_purpose;
-use base64::Engine as _;
-use image::imageops::FilterType;
-use image::{GenericImage, ImageBuffer, Rgb, RgbImage};
-use log::{debug, error, info};
-use macro_deck_driver::MacroDeck;
-use serde_json::json;
+use image::{imageops, DynamicImage, ImageFormat, RgbImage};
+use log::{debug, error, info, warn};
+use macro_deck_driver::{DeviceInfo, DeviceTree, MacroDeck, NodeKind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 
-use super::models::{Config, Message};
+use super::client::request;
+use super::models::{
+    ButtonConfig, Config, FlashOptions, FlashRequest, IconLayout, IconSource, Message,
+};
+use super::render::{load_icon, render_background, render_button, Label, BACK_ICON};
+use super::start::{read_and_parse_config, MAX_TRIES};
+use super::state::device_state_dir;
+use super::toggle::apply_toggle_states;
 
-pub fn flash_device(deck: &MacroDeck, config: &Config) {
-    debug!("Creating aio images...");
+#[derive(Serialize, Clone, Debug)]
+pub struct ButtonReport {
+    pub path: String,
+    pub index: usize,
+    pub row: u32,
+    pub column: u32,
+    pub x: u32,
+    pub y: u32,
+    pub icon: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
 
-    if config.buttons.is_none() {
-        error!("No buttons found in config");
-        return;
+pub struct ComposedFolder {
+    pub dir: String,
+    pub aio: RgbImage,
+    /// The area of each button in `aio`, by index
+    pub cells: BTreeMap<usize, RgbImage>,
+    pub buttons: Vec<ButtonReport>,
+}
+
+impl ComposedFolder {
+    /// Device paths and images of the files storing this folder.
+    pub fn files(&self, layout: IconLayout) -> Vec<(String, &RgbImage)> {
+        match layout {
+            IconLayout::Aio => vec![(aio_path(&self.dir), &self.aio)],
+            IconLayout::Buttons => self
+                .cells
+                .iter()
+                .map(|(&idx, cell)| (button_path(&self.dir, idx), cell))
+                .collect(),
+        }
     }
+}
 
-    // Group icons by directory
-    let mut dir_icons: HashMap<String, HashMap<usize, String>> = HashMap::new();
-    for (key, button) in config.buttons.as_ref().unwrap() {
-        if let Some(icon) = &button.icon {
-            let path = match PathBuf::from_str(key) {
-                Ok(path) => path,
-                Err(_) => {
-                    error!("Invalid icon path: {}", key);
-                    continue;
-                }
-            };
+/// Split a button path into its folder and index.
+fn split_button_path(key: &str) -> Option<(String, usize)> {
+    let path = PathBuf::from_str(key).ok()?;
+    let parent = path.parent()?.to_str()?;
+    let idx = path.file_name()?.to_str()?.parse::<usize>().ok()?;
+
+    Some((parent.to_string(), idx))
+}
+
+type FolderButtons<'a> = BTreeMap<usize, (&'a String, &'a ButtonConfig)>;
+
+/// Buttons grouped by folder. Sorted, so a folder always comes before its
+/// subfolders.
+fn group_buttons(config: &Config) -> BTreeMap<String, FolderButtons<'_>> {
+    let mut dir_buttons: BTreeMap<String, FolderButtons> = BTreeMap::new();
+
+    for (key, button) in config.buttons.iter().flatten() {
+        let (parent, idx) = match split_button_path(key) {
+            Some(split) => split,
+            None => {
+                error!("Invalid icon path: {}", key);
+                continue;
+            }
+        };
+
+        dir_buttons
+            .entry(parent)
+            .or_default()
+            .insert(idx, (key, button));
+    }
+
+    dir_buttons
+}
+
+/// Put the `back_button` of the config in every subfolder, replacing the
+/// button in its slot. Only the icon is drawn, what a press does is up to
+/// the firmware. Does nothing without `back_button`.
+pub fn add_back_buttons(config: &mut Config, info: &DeviceInfo) {
+    let back = match &config.back_button {
+        Some(back) => back.clone(),
+        None => return,
+    };
+    let slots = (info.buttons_per_row * info.num_of_rows) as usize;
+    let slot = back.slot.unwrap_or(slots.saturating_sub(1));
+
+    let buttons = config.buttons.get_or_insert_with(HashMap::new);
+    let folders: BTreeSet<String> = buttons
+        .keys()
+        .filter_map(|key| split_button_path(key).map(|(parent, _)| parent))
+        .collect();
+
+    for folder in folders {
+        // Profiles are the top level folders, there is nothing to go back to
+        if Path::new(&folder).components().count() < 3 {
+            continue;
+        }
+
+        let key = button_key(&folder, slot);
+        let button = buttons.entry(key.clone()).or_default();
+        if button.action.is_some() || button.icon.is_some() || button.label.is_some() {
+            warn!("{} is replaced by the back button", key);
+        }
+
+        *button = ButtonConfig {
+            icon: Some(
+                back.icon
+                    .clone()
+                    .unwrap_or(IconSource::Embedded(BACK_ICON.to_string())),
+            ),
+            label: back.label.clone(),
+            style: back.style.clone(),
+            ..Default::default()
+        };
+    }
+}
+
+/// Config key of the button `idx` of a folder
+fn button_key(dir: &str, idx: usize) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), idx)
+}
+
+/// Render the aio image of a folder.
+fn compose_folder(
+    config: &Config,
+    info: &DeviceInfo,
+    dir: &str,
+    buttons: &FolderButtons,
+) -> ComposedFolder {
+    let width = info.width;
+    let height = info.height - info.gap_size - info.status_bar_height;
+    let default_style = config.style.clone().unwrap_or_default();
+
+    let folder = config.folders.as_ref().and_then(|folders| folders.get(dir));
+    let background_image = folder
+        .and_then(|folder| folder.background_image.as_ref())
+        .and_then(|source| {
+            let image = load_icon(source, &config.base_dir);
+            if image.is_none() {
+                error!("Failed to load background image: {}", dir);
+            }
+            image
+        });
+    let mut aio = render_background(
+        width,
+        height,
+        folder.and_then(|folder| folder.background.as_deref()),
+        background_image.as_ref(),
+    );
 
-            let parent = match path.parent() {
-                Some(parent) => parent.to_str().unwrap(),
+    let mut reports = vec![];
+    for (&idx, &(key, button)) in buttons {
+        let col = (idx as u32) % info.buttons_per_row;
+        let row = (idx as u32) / info.buttons_per_row;
+
+        let x = col * (info.button_size + info.gap_size);
+        let y = row * (info.button_size + info.gap_size);
+
+        let mut report = ButtonReport {
+            path: key.clone(),
+            index: idx,
+            row,
+            column: col,
+            x,
+            y,
+            icon: button.icon.is_some(),
+            label: button.label.clone(),
+            error: None,
+        };
+
+        if row >= info.num_of_rows {
+            error!("Button outside of the grid: {}", key);
+            report.error = Some("Button outside of the grid".to_string());
+            reports.push(report);
+            continue;
+        }
+
+        let icon = match &button.icon {
+            Some(icon) => match load_icon(icon, &config.base_dir) {
+                Some(icon) => Some(icon),
                 None => {
-                    error!("Invalid icon path: {}", key);
+                    error!("Failed to load icon: {}", idx);
+                    report.error = Some("Failed to load icon".to_string());
+                    reports.push(report);
                     continue;
                 }
-            };
+            },
+            None => None,
+        };
 
-            let idx = match path.file_name() {
-                Some(idx) => idx,
-                None => {
-                    error!("Invalid icon path: {}", key);
-                    continue;
+        let style = match &button.style {
+            Some(style) => style.merge(&default_style),
+            None => default_style.clone(),
+        };
+        let label = Label::from_button(button);
+        let cell = render_button(icon.as_ref(), label.as_ref(), &style, info.button_size);
+
+        imageops::overlay(&mut aio, &cell, x as i64, y as i64);
+        reports.push(report);
+    }
+
+    // Cut the buttons out of the composed image, so the folder background
+    // shows through their transparent parts
+    let aio = DynamicImage::ImageRgba8(aio).to_rgb8();
+    let cells = reports
+        .iter()
+        .filter(|report| report.error.is_none())
+        .map(|report| {
+            let cell =
+                imageops::crop_imm(&aio, report.x, report.y, info.button_size, info.button_size);
+            (report.index, cell.to_image())
+        })
+        .collect();
+
+    ComposedFolder {
+        dir: dir.to_string(),
+        aio,
+        cells,
+        buttons: reports,
+    }
+}
+
+/// Render the folders on a worker pool and pass them to `on_folder` in order,
+/// each as soon as it and the ones before it are done. Stops rendering when
+/// `on_folder` returns false.
+fn compose_in_order(
+    config: &Config,
+    info: &DeviceInfo,
+    mut on_folder: impl FnMut(ComposedFolder) -> bool,
+) {
+    let groups: Vec<(String, FolderButtons)> = group_buttons(config).into_iter().collect();
+    let workers = thread::available_parallelism()
+        .map_or(1, |workers| workers.get())
+        .min(groups.len());
+
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let (groups, next, stop) = (&groups, &next, &stop);
+
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let (dir, buttons) = match groups.get(index) {
+                        Some(group) => group,
+                        None => break,
+                    };
+
+                    let folder = compose_folder(config, info, dir, buttons);
+                    if sender.send((index, folder)).is_err() {
+                        break;
+                    }
                 }
-            };
-            let idx = idx.to_str().unwrap();
-            let idx = match idx.parse::<usize>() {
-                Ok(idx) => idx,
-                Err(_) => {
-                    error!("Invalid icon path: {}", key);
-                    continue;
+            });
+        }
+        drop(sender);
+
+        // Folders can finish out of order, hold them until their turn
+        let mut finished = BTreeMap::new();
+        let mut expected = 0;
+        for (index, folder) in receiver {
+            finished.insert(index, folder);
+
+            while let Some(folder) = finished.remove(&expected) {
+                expected += 1;
+
+                if !on_folder(folder) {
+                    stop.store(true, Ordering::Relaxed);
+                    return;
                 }
-            };
+            }
+        }
+    });
+}
+
+/// Render the aio image of every folder in the config, sorted by folder.
+pub fn compose_folders(config: &Config, info: &DeviceInfo) -> Vec<ComposedFolder> {
+    let mut config = config.clone();
+    add_back_buttons(&mut config, info);
+
+    let mut folders = vec![];
+    compose_in_order(&config, info, |folder| {
+        folders.push(folder);
+        true
+    });
+
+    folders
+}
+
+/// Outcome of a folder in the last flash, ordered from best to worst
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum FolderStatus {
+    Unchanged,
+    Uploaded,
+    /// Uploaded and read back successfully
+    Verified,
+    /// Read back with a mismatch and uploaded again
+    Reuploaded,
+    Failed,
+}
+
+impl fmt::Display for FolderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The same name as in the messages
+        match serde_json::to_value(self) {
+            Ok(Value::String(name)) => f.write_str(&name),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// Step of a flash, each going through its folders once
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashPhase {
+    /// Rendering every folder and writing the changed files to staging
+    #[default]
+    Staging,
+    /// Reading back the files about to be overwritten
+    Backup,
+    /// Writing the changed files in place
+    Writing,
+}
+
+impl fmt::Display for FlashPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(Value::String(name)) => f.write_str(&name),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// Sent by the daemon after each folder of each phase, as the value of a
+/// `flashProgress` message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FlashProgress {
+    #[serde(default)]
+    pub phase: FlashPhase,
+    pub dir: String,
+    pub status: FolderStatus,
+    /// Folders of the phase handled so far, including this one
+    pub done: usize,
+    pub total: usize,
+    /// Bytes written to or backed up from the device so far
+    pub bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
 
-            if let Some(dir) = dir_icons.get_mut(parent) {
-                dir.insert(idx, icon.clone());
-            } else {
-                let mut dir = HashMap::new();
-                dir.insert(idx, icon.clone());
-                dir_icons.insert(parent.to_string(), dir);
+/// Sent by the daemon when the flash finishes, as the value of a `flash` message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FlashResult {
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub folders: BTreeMap<String, FolderStatus>,
+}
+
+/// Path of the image of a folder in the aio layout.
+pub fn aio_path(dir: &str) -> String {
+    format!("{}/aio.jpg", dir.trim_end_matches('/'))
+}
+
+/// Path of the image of a button in the buttons layout.
+pub fn button_path(dir: &str, idx: usize) -> String {
+    format!("{}/{}.jpg", dir.trim_end_matches('/'), idx)
+}
+
+/// Write an image to the device, retrying on failure. Returns the size of
+/// the file written.
+fn upload(deck: &MacroDeck, path: &str, image: &RgbImage) -> Result<usize, String> {
+    let mut tries = 0;
+    loop {
+        match deck.set_icon(path, DynamicImage::ImageRgb8(image.clone())) {
+            Ok(size) => return Ok(size),
+            Err(e) => {
+                tries += 1;
+                if tries >= MAX_TRIES {
+                    return Err(format!("Failed to write {}: {}", path, e));
+                }
+
+                debug!("Failed to write {}: {}", path, e);
+                debug!("Retrying...");
             }
         }
     }
+}
 
-    let info = match deck.get_info() {
-        Ok(info) => info,
-        Err(_) => {
-            error!("Failed to get device info");
-            return;
+/// Write `data` to the device as is, retrying like `upload`.
+fn upload_bytes(deck: &MacroDeck, path: &str, data: &[u8]) -> Result<(), String> {
+    let mut tries = 0;
+    loop {
+        match deck.write_file(path, data) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                tries += 1;
+                if tries >= MAX_TRIES {
+                    return Err(format!("Failed to write {}: {}", path, e));
+                }
+
+                debug!("Failed to write {}: {}", path, e);
+                debug!("Retrying...");
+            }
         }
+    }
+}
+
+/// Remove the folders and files on the device that are not part of `desired`.
+fn remove_extra(deck: &MacroDeck, desired: &DeviceTree) -> Result<(), String> {
+    let existing = deck
+        .get_tree()
+        .map_err(|e| format!("Failed to list directory: {}", e))?;
+
+    for path in existing.diff(desired).removed {
+        debug!("Removing stale path: {}", path);
+        let result = match existing.get(&path).map(|node| node.kind) {
+            Some(NodeKind::File) => deck.remove_icon(&path),
+            _ => deck.remove_folder(&path),
+        };
+        result.map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Remove the folders and files that were not written by this flash, the
+/// staging folders included.
+fn remove_stale(
+    deck: &MacroDeck,
+    folders: &[ComposedFolder],
+    files: &HashMap<String, String>,
+) -> Result<(), String> {
+    let mut desired = DeviceTree::new();
+    for folder in folders {
+        desired.insert(&folder.dir, NodeKind::Folder, None);
+    }
+    for file in files.keys() {
+        desired.insert(file, NodeKind::File, None);
+    }
+
+    remove_extra(deck, &desired)
+}
+
+/// The profile, i.e. top level folder, a device path belongs to. `None` for
+/// the files at the root.
+fn profile_of(path: &str) -> Option<&str> {
+    let (profile, _) = path.strip_prefix('/')?.split_once('/')?;
+    Some(profile)
+}
+
+/// Name of the top level folder the new files of `profile` are written to
+/// before they replace the old ones.
+fn staging_profile(profile: &str) -> String {
+    format!("{}-staging", profile)
+}
+
+/// Where `path` is written while its profile is staged. `None` for the files
+/// at the root, which have no profile to stage.
+fn staging_path(path: &str) -> Option<String> {
+    let profile = profile_of(path)?;
+    let rest = &path[profile.len() + 1..];
+
+    Some(format!("/{}{}", staging_profile(profile), rest))
+}
+
+/// Forget the files backed up by the last flash.
+fn clear_backup(state_dir: &Path) {
+    let _ = fs::remove_dir_all(state_dir.join("backup"));
+}
+
+/// Read back a file of the device about to be overwritten and keep it in
+/// `state_dir`, at its device path. Returns the size of the file.
+fn backup_file(deck: &MacroDeck, state_dir: &Path, path: &str) -> Result<usize, String> {
+    let data = deck
+        .read_file(path)
+        .map_err(|e| format!("Failed to back up {}: {}", path, e))?;
+
+    let file = state_dir.join("backup").join(path.trim_start_matches('/'));
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    fs::write(&file, &data).map_err(|e| e.to_string())?;
+
+    Ok(data.len())
+}
+
+fn load_backup(root: &Path, current: &Path, files: &mut Vec<(String, Vec<u8>)>) {
+    let entries = match fs::read_dir(current) {
+        Ok(entries) => entries,
+        Err(_) => return,
     };
-    let width = info.width;
-    let height = info.height - info.gap_size - info.status_bar_height;
 
-    // Create a new image for each directory
-    let mut aio_hashmap = HashMap::new();
-    for (dir, icons) in dir_icons {
-        let mut aio: RgbImage = ImageBuffer::from_pixel(width, height, Rgb([0, 0, 0]));
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            load_backup(root, &path, files);
+            continue;
+        }
 
-        for (idx, icon) in icons {
-            let icon = match general_purpose::STANDARD.decode(icon) {
-                Ok(icon) => icon,
-                Err(_) => {
-                    error!("Failed to decode icon: {}", idx);
-                    continue;
+        let file = path.strip_prefix(root).unwrap_or(&path);
+        let file = format!("/{}", file.display());
+        match fs::read(&path) {
+            Ok(data) => files.push((file, data)),
+            Err(e) => warn!("Failed to read backup {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Write the backed up files over the new ones, and remove everything that
+/// was not on the device before the flash.
+fn rollback(deck: &MacroDeck, state_dir: &Path, before: &DeviceTree) -> Result<(), String> {
+    let backup = state_dir.join("backup");
+    let mut files = vec![];
+    load_backup(&backup, &backup, &mut files);
+
+    for (path, data) in files {
+        upload_bytes(deck, &path, &data)?;
+    }
+
+    remove_extra(deck, before)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hash_image(image: &RgbImage) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(image.width().to_le_bytes());
+    hasher.update(image.height().to_le_bytes());
+    hasher.update(image.as_raw());
+
+    to_hex(&hasher.finalize())
+}
+
+/// Encode the image the same way `MacroDeck::set_icon` does.
+fn encode_jpeg(image: &RgbImage) -> Result<Vec<u8>, String> {
+    let mut jpeg = Vec::new();
+    DynamicImage::ImageRgb8(image.clone())
+        .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+        .map_err(|e| e.to_string())?;
+
+    Ok(jpeg)
+}
+
+/// Check that the device stores `image` at `path`.
+fn check(deck: &MacroDeck, path: &str, image: &RgbImage) -> Result<(), String> {
+    // The device stores the JPEG as sent, and encoding it again here gives the
+    // same bytes, so the file read back has to hash the same
+    let jpeg = encode_jpeg(image)?;
+    let stored = deck
+        .read_file(path)
+        .map_err(|e| format!("failed to read back: {}", e))?;
+
+    if Sha256::digest(&stored) != Sha256::digest(&jpeg) {
+        return Err(format!("hash mismatch, {} bytes read back", stored.len()));
+    }
+
+    Ok(())
+}
+
+/// Read the image back and upload it again until it matches. Returns whether
+/// it had to be uploaded again.
+fn verify(deck: &MacroDeck, path: &str, image: &RgbImage) -> Result<bool, String> {
+    let mut reuploaded = false;
+    let mut tries = 0;
+    loop {
+        match check(deck, path, image) {
+            Ok(()) => return Ok(reuploaded),
+            Err(e) => {
+                tries += 1;
+                if tries >= MAX_TRIES {
+                    return Err(format!("Failed to verify {}: {}", path, e));
                 }
-            };
 
-            let icon = match image::load_from_memory(&icon) {
-                Ok(icon) => icon,
-                Err(_) => {
-                    error!("Failed to load icon: {}", idx);
-                    continue;
+                warn!("Failed to verify {}: {}", path, e);
+                debug!("Uploading again...");
+                upload(deck, path, image)?;
+                reuploaded = true;
+            }
+        }
+    }
+}
+
+/// Upload a file, and verify it if asked to. Returns the size of the file.
+fn write_file(
+    deck: &MacroDeck,
+    path: &str,
+    image: &RgbImage,
+    verify_file: bool,
+) -> Result<(FolderStatus, usize), String> {
+    debug!("Writing icon: {}", path);
+    let size = upload(deck, path, image)?;
+
+    if !verify_file {
+        return Ok((FolderStatus::Uploaded, size));
+    }
+
+    debug!("Verifying icon: {}", path);
+    match verify(deck, path, image)? {
+        true => Ok((FolderStatus::Reuploaded, size)),
+        false => Ok((FolderStatus::Verified, size)),
+    }
+}
+
+/// Device paths grouped by the folder holding them.
+fn group_by_dir<'a>(
+    paths: impl IntoIterator<Item = &'a String>,
+) -> BTreeMap<String, Vec<&'a String>> {
+    let mut dirs: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+    for path in paths {
+        let dir = Path::new(path).parent().unwrap_or(Path::new("/"));
+        dirs.entry(dir.display().to_string())
+            .or_default()
+            .push(path);
+    }
+
+    dirs
+}
+
+/// Handle the files of `dirs` folder by folder, calling `on_progress` after
+/// each folder. `handle` returns the status of a file and its size.
+fn run_phase(
+    phase: FlashPhase,
+    dirs: &BTreeMap<String, Vec<&String>>,
+    bytes: &mut usize,
+    on_progress: &mut dyn FnMut(&FlashProgress),
+    mut handle: impl FnMut(&str) -> Result<(FolderStatus, usize), String>,
+) -> Result<(), String> {
+    for (idx, (dir, paths)) in dirs.iter().enumerate() {
+        let mut progress = FlashProgress {
+            phase,
+            dir: dir.clone(),
+            status: FolderStatus::Unchanged,
+            done: idx + 1,
+            total: dirs.len(),
+            bytes: *bytes,
+            error: None,
+        };
+
+        let mut result = Ok(());
+        for path in paths {
+            match handle(path) {
+                Ok((status, size)) => {
+                    *bytes += size;
+                    progress.status = progress.status.max(status);
                 }
-            };
+                Err(e) => {
+                    progress.status = FolderStatus::Failed;
+                    progress.error = Some(e.clone());
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        progress.bytes = *bytes;
+        on_progress(&progress);
+        result?;
+    }
+
+    Ok(())
+}
+
+fn report(statuses: &BTreeMap<String, FolderStatus>) {
+    for (dir, status) in statuses {
+        info!("{}: {}", dir, status);
+    }
+}
+
+/// What the last successful flash wrote. The firmware only stores images, so
+/// it is kept locally.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Manifest {
+    /// How the files are stored, so later updates of a single button write
+    /// the same files
+    layout: Option<IconLayout>,
+    /// Content hash of each file, by device path
+    files: HashMap<String, String>,
+}
 
-            // Resize the icon to fit the button size
-            let icon = icon
-                .resize_exact(info.button_size, info.button_size, FilterType::Lanczos3)
-                .to_rgb8();
+fn load_manifest(state_dir: &Path) -> Manifest {
+    fs::read_to_string(state_dir.join("manifest.json"))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_manifest(state_dir: &Path, manifest: &Manifest) -> Result<(), String> {
+    fs::create_dir_all(state_dir).map_err(|e| e.to_string())?;
+    fs::write(
+        state_dir.join("manifest.json"),
+        serde_json::to_string_pretty(manifest).unwrap(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Make the next flash upload everything, after the files on the device were
+/// changed by other means.
+pub fn forget_manifest(state_dir: &Path) {
+    let manifest = Manifest {
+        layout: load_manifest(state_dir).layout,
+        files: HashMap::new(),
+    };
+    let _ = save_manifest(state_dir, &manifest);
+}
 
-            let col = (idx as u32) % info.buttons_per_row;
-            let row = (idx as u32) / info.buttons_per_row;
+/// Render a button with a new icon, or without one, and upload the file
+/// storing it, without a full flash. The change is kept in `config`, so later
+/// updates of the same folder keep it.
+pub fn update_button_icon(
+    deck: &MacroDeck,
+    config: &mut Config,
+    state_dir: &Path,
+    button: &str,
+    icon: Option<IconSource>,
+) -> Result<(), String> {
+    let (dir, idx) = split_button_path(button).ok_or("Invalid button path")?;
+    if let Some(icon) = &icon {
+        if load_icon(icon, &config.base_dir).is_none() {
+            return Err("Failed to load icon".to_string());
+        }
+    }
 
-            let x = col * (info.button_size + info.gap_size);
-            let y = row * (info.button_size + info.gap_size);
+    let info = deck
+        .get_info()
+        .map_err(|e| format!("Failed to get device info: {}", e))?;
 
-            if aio.copy_from(&icon, x, y).is_err() {
-                error!("Failed to copy icon: {}", idx);
+    let mut updated = config.clone();
+    apply_toggle_states(&mut updated, state_dir);
+    updated
+        .buttons
+        .get_or_insert_with(HashMap::new)
+        .entry(button.to_string())
+        .or_default()
+        .icon = icon;
+
+    // The back buttons are not kept in `config`, they are added again on
+    // every update
+    let mut composed = updated.clone();
+    add_back_buttons(&mut composed, &info);
+    let groups = group_buttons(&composed);
+    let folder = compose_folder(&composed, &info, &dir, &groups[&dir]);
+    if let Some(error) = folder
+        .buttons
+        .iter()
+        .find(|report| report.index == idx)
+        .and_then(|report| report.error.as_ref())
+    {
+        return Err(error.clone());
+    }
+
+    // The layout of the last flash, which may come from `flash --layout`
+    let mut manifest = load_manifest(state_dir);
+    let layout = manifest.layout.or(updated.layout).unwrap_or_default();
+    let (path, image) = match layout {
+        IconLayout::Aio => (aio_path(&dir), &folder.aio),
+        IconLayout::Buttons => (button_path(&dir, idx), &folder.cells[&idx]),
+    };
+    upload(deck, &path, image)?;
+
+    // The device no longer matches the flashed config, upload it again next time
+    if manifest.files.remove(&path).is_some() {
+        if let Err(e) = save_manifest(state_dir, &manifest) {
+            warn!("Failed to save flash manifest: {}", e);
+        }
+    }
+
+    *config = updated;
+    Ok(())
+}
+
+/// Write the aio image of every changed folder to the device.
+///
+/// The firmware cannot rename folders, so the changed files are first written
+/// to a staging copy of their profile, e.g. `/default-staging`. Nothing the
+/// device shows is touched until every file made it there. They are then
+/// written in place and the staging copies removed. The files being
+/// overwritten are read back into `state_dir` first, and put back if writing
+/// them fails.
+///
+/// `on_progress` is called after each folder of each phase.
+pub fn flash_device(
+    deck: &MacroDeck,
+    config: &Config,
+    state_dir: &Path,
+    options: FlashOptions,
+    on_progress: &mut dyn FnMut(&FlashProgress),
+) -> Result<(), String> {
+    debug!("Creating aio images...");
+
+    if config.buttons.is_none() {
+        return Err("No buttons found in config".to_string());
+    }
+
+    let info = deck
+        .get_info()
+        .map_err(|e| format!("Failed to get device info: {}", e))?;
+    let mut config = config.clone();
+    apply_toggle_states(&mut config, state_dir);
+    add_back_buttons(&mut config, &info);
+    let config = &config;
+
+    let previous = if options.full {
+        HashMap::new()
+    } else {
+        load_manifest(state_dir).files
+    };
+    let existing = deck
+        .get_tree()
+        .map_err(|e| format!("Failed to list directory: {}", e))?;
+
+    let layout = options.layout.or(config.layout).unwrap_or_default();
+
+    // Stage the changed images while the rest are being rendered
+    let total = group_buttons(config).len();
+    let mut folders = vec![];
+    let mut manifest = HashMap::new();
+    let mut changed = BTreeSet::new();
+    let mut result = Ok(());
+    let mut statuses = BTreeMap::new();
+    let mut bytes = 0;
+    compose_in_order(config, &info, |folder| {
+        let mut progress = FlashProgress {
+            phase: FlashPhase::Staging,
+            dir: folder.dir.clone(),
+            status: FolderStatus::Unchanged,
+            done: statuses.len() + 1,
+            total,
+            bytes,
+            error: None,
+        };
+
+        // A button that failed to render would be written without its icon
+        let mut error = folder.buttons.iter().find_map(|report| {
+            let e = report.error.as_ref()?;
+            Some(format!("{}: {}", report.path, e))
+        });
+        for (path, image) in folder.files(layout) {
+            if error.is_some() {
+                break;
+            }
+            let hash = hash_image(image);
+
+            // Skip files that are unchanged and still on the device
+            if previous.get(&path) == Some(&hash) && existing.contains(&path) {
+                debug!("Unchanged: {}", path);
+                manifest.insert(path, hash);
                 continue;
             }
+            manifest.insert(path.clone(), hash);
+            changed.insert(path.clone());
+
+            // The files at the root are only written in place
+            let staged = match staging_path(&path) {
+                Some(staged) => staged,
+                None => {
+                    progress.status = progress.status.max(FolderStatus::Uploaded);
+                    continue;
+                }
+            };
+            match write_file(deck, &staged, image, options.verify) {
+                Ok((status, size)) => {
+                    bytes += size;
+                    progress.status = progress.status.max(status);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        progress.bytes = bytes;
+        if let Some(e) = error {
+            statuses.insert(folder.dir.clone(), FolderStatus::Failed);
+            progress.status = FolderStatus::Failed;
+            progress.error = Some(e.clone());
+            on_progress(&progress);
+
+            result = Err(e);
+            return false;
         }
 
-        aio_hashmap.insert(dir, aio);
+        statuses.insert(folder.dir.clone(), progress.status);
+        on_progress(&progress);
+
+        folders.push(folder);
+        true
+    });
+    report(&statuses);
+
+    let images: HashMap<String, &RgbImage> = folders
+        .iter()
+        .flat_map(|folder| folder.files(layout))
+        .collect();
+
+    if let Err(e) = result {
+        if let Err(cleanup_error) = remove_extra(deck, &existing) {
+            warn!("Failed to remove the staging folders: {}", cleanup_error);
+        }
+        return Err(format!("{}, the device was not changed", e));
     }
 
-    // Format the device
-    info!("Formatting device...");
-    if deck.remove_folder("/").is_err() {
-        error!("Failed to format device");
-        return;
+    clear_backup(state_dir);
+    let backed_up = group_by_dir(changed.iter().filter(|path| existing.contains(path)));
+    let result = run_phase(
+        FlashPhase::Backup,
+        &backed_up,
+        &mut bytes,
+        on_progress,
+        |path| backup_file(deck, state_dir, path).map(|size| (FolderStatus::Unchanged, size)),
+    )
+    .and_then(|()| {
+        run_phase(
+            FlashPhase::Writing,
+            &group_by_dir(&changed),
+            &mut bytes,
+            on_progress,
+            |path| write_file(deck, path, images[path], options.verify),
+        )
+    });
+
+    if let Err(e) = result {
+        info!("Flash failed, restoring the previous images...");
+        return match rollback(deck, state_dir, &existing) {
+            Ok(()) => Err(format!("{}, restored the previous images", e)),
+            Err(rollback_error) => {
+                // The device content is unknown, upload everything next time
+                forget_manifest(state_dir);
+
+                Err(format!(
+                    "{}, failed to restore the previous images: {}",
+                    e, rollback_error
+                ))
+            }
+        };
     }
 
-    // Write the images to the device
-    for (dir, aio) in aio_hashmap {
-        let dir = format!("{}/aio.jpg", dir);
-        debug!("Writing icon: {}", dir);
+    let manifest = Manifest {
+        layout: Some(layout),
+        files: manifest,
+    };
+    if let Err(e) = save_manifest(state_dir, &manifest) {
+        warn!("Failed to save flash manifest: {}", e);
+    }
+    remove_stale(deck, &folders, &manifest.files)?;
 
-        if deck
-            .set_icon(&dir, image::DynamicImage::ImageRgb8(aio))
-            .is_err()
-        {
-            error!("Failed to write icon: {}", dir);
-            continue;
+    info!("Flash complete!");
+    Ok(())
+}
+
+/// Parse device info given as `WxH,cols,rows,gap`, e.g. `480x320,4,3,8`.
+pub fn parse_device_info(value: &str) -> Option<DeviceInfo> {
+    let mut parts = value.split(',');
+    let (width, height) = parts.next()?.split_once('x')?;
+    let width: u32 = width.trim().parse().ok()?;
+    let height: u32 = height.trim().parse().ok()?;
+    let buttons_per_row: u32 = parts.next()?.trim().parse().ok()?;
+    let num_of_rows: u32 = parts.next()?.trim().parse().ok()?;
+    let gap_size: u32 = parts.next()?.trim().parse().ok()?;
+    if parts.next().is_some() || buttons_per_row == 0 || num_of_rows == 0 {
+        return None;
+    }
+
+    // The buttons and gaps have to fit on the screen
+    let gaps = (buttons_per_row - 1).checked_mul(gap_size)?;
+    let button_size = width.checked_sub(gaps)? / buttons_per_row;
+    let rows = button_size
+        .checked_add(gap_size)?
+        .checked_mul(num_of_rows)?;
+    height.checked_sub(rows)?;
+
+    Some(DeviceInfo::new(
+        width,
+        height,
+        buttons_per_row,
+        num_of_rows,
+        gap_size,
+    ))
+}
+
+pub fn request_device_info(tcp_port: &str) -> Option<DeviceInfo> {
+    let value = request(tcp_port, "getInfo", None).ok()?;
+    let value: Vec<u32> = serde_json::from_value(value).ok()?;
+    if value.len() != 5 {
+        return None;
+    }
+
+    Some(DeviceInfo::new(
+        value[0], value[1], value[2], value[3], value[4],
+    ))
+}
+
+/// Render the images and a layout report to `out` without flashing.
+pub fn dry_run(
+    config: &Config,
+    info: &DeviceInfo,
+    layout: IconLayout,
+    out: &Path,
+) -> Result<(), String> {
+    let folders = compose_folders(config, info);
+
+    let mut report = vec![];
+    for mut folder in folders {
+        let mut images = vec![];
+        for (file, image) in folder.files(layout) {
+            let file = Path::new(file.trim_start_matches('/')).to_path_buf();
+            let path = out.join(&file);
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            image
+                .save_with_format(&path, ImageFormat::Jpeg)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+            images.push(file.display().to_string());
         }
+
+        folder.buttons.sort_by_key(|button| button.index);
+        report.push(json!({
+            "path": folder.dir,
+            "images": images,
+            "buttons": folder.buttons,
+        }));
     }
 
-    info!("Flash complete!");
+    let report = json!({
+        "device": {
+            "width": info.width,
+            "height": info.height,
+            "buttons_per_row": info.buttons_per_row,
+            "num_of_rows": info.num_of_rows,
+            "gap_size": info.gap_size,
+            "button_size": info.button_size,
+            "status_bar_height": info.status_bar_height,
+        },
+        "layout": layout,
+        "folders": report,
+    });
+
+    let path = out.join("layout.json");
+    fs::write(&path, serde_json::to_string_pretty(&report).unwrap())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
 }
 
-pub fn flash(tcp_port: Option<String>, config_path: Option<String>) {
+/// Open the device and flash it without going through the driver.
+fn flash_direct(port: &str, config_path: Option<String>, mut options: FlashOptions) {
+    let config = match read_and_parse_config(&config_path.unwrap_or("config.json".to_string())) {
+        Some(config) => config,
+        None => {
+            eprintln!("Failed to load config");
+            std::process::exit(1);
+        }
+    };
+
+    options.layout = options.layout.or(config.device_layout(port));
+
+    let deck = match MacroDeck::new(port) {
+        Ok(deck) => deck,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", port, e);
+            eprintln!(
+                "If the driver is running on this port, stop it first or flash without --port"
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let result = flash_device(
+        &deck,
+        &config,
+        &device_state_dir(port),
+        options,
+        &mut print_progress,
+    );
+
+    eprintln!();
+    match result {
+        Ok(()) => println!("Flash complete"),
+        Err(e) => {
+            eprintln!("Flash failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn flash(
+    tcp_port: Option<String>,
+    config_path: Option<String>,
+    port: Option<String>,
+    dry_run_out: Option<String>,
+    device_info: Option<String>,
+    options: FlashOptions,
+) {
     let tcp_port = tcp_port.unwrap_or("8964".to_string());
+
+    if let Some(out) = dry_run_out {
+        let config = match read_and_parse_config(&config_path.unwrap_or("config.json".to_string()))
+        {
+            Some(config) => config,
+            None => {
+                eprintln!("Failed to load config");
+                std::process::exit(1);
+            }
+        };
+
+        let info = match device_info {
+            Some(device_info) => match parse_device_info(&device_info) {
+                Some(info) => info,
+                None => {
+                    eprintln!("Invalid device info, expected WxH,cols,rows,gap");
+                    std::process::exit(1);
+                }
+            },
+            None => match request_device_info(&tcp_port) {
+                Some(info) => info,
+                None => {
+                    eprintln!(
+                        "Failed to get device info from TCP port {}, pass --device-info to run offline",
+                        tcp_port
+                    );
+                    std::process::exit(1);
+                }
+            },
+        };
+
+        let layout = options.layout.or(config.layout).unwrap_or_default();
+        match dry_run(&config, &info, layout, Path::new(&out)) {
+            Ok(()) => println!("Wrote dry run to {}", out),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(port) = port {
+        flash_direct(&port, config_path, options);
+        return;
+    }
+
     let mut stream = match TcpStream::connect(format!("127.0.0.1:{}", tcp_port)) {
         Ok(stream) => stream,
         Err(_) => {
             eprintln!("Failed to connect to TCP port: {}", tcp_port);
-            return;
+            std::process::exit(1);
         }
     };
 
+    // The daemon may run in another directory
+    let config_path = config_path.map(|path| {
+        fs::canonicalize(&path)
+            .map(|path| path.display().to_string())
+            .unwrap_or(path)
+    });
+
+    let request = FlashRequest {
+        config_path,
+        options,
+    };
     let msg = Message {
         type_: "flash".to_string(),
-        value: config_path.map(|v| json!(v)),
+        value: Some(json!(request)),
     };
 
     let json = serde_json::to_string(&msg).unwrap();
     if writeln!(stream, "{}", json).is_err() {
         eprintln!("Failed to send message");
-        return;
+        std::process::exit(1);
     }
 
     println!("Sent flash command to TCP port");
 
-    // TODO response
+    for line in BufReader::new(&stream).lines() {
+        let msg: Message = match line.map(|line| serde_json::from_str(&line)) {
+            Ok(Ok(msg)) => msg,
+            _ => break,
+        };
+
+        match msg.type_.as_str() {
+            "flashProgress" => {
+                if let Some(progress) = msg
+                    .value
+                    .and_then(|value| serde_json::from_value(value).ok())
+                {
+                    print_progress(&progress);
+                }
+            }
+            "flash" => {
+                let result: FlashResult = match msg
+                    .value
+                    .and_then(|value| serde_json::from_value(value).ok())
+                {
+                    Some(result) => result,
+                    None => break,
+                };
+
+                eprintln!();
+                if result.success {
+                    println!("Flash complete");
+                    return;
+                }
+
+                eprintln!(
+                    "Flash failed: {}",
+                    result.error.unwrap_or("unknown error".to_string())
+                );
+                std::process::exit(1);
+            }
+            _ => {}
+        }
+    }
+
+    eprintln!();
+    eprintln!("Connection closed before the flash finished");
+    std::process::exit(1);
+}
+
+fn print_progress(progress: &FlashProgress) {
+    const WIDTH: usize = 30;
+
+    let filled = WIDTH * progress.done / progress.total.max(1);
+    if let Some(error) = &progress.error {
+        eprintln!("\r\x1b[K{}: {}", progress.dir, error);
+    }
+    // Each phase fills the bar again
+    let folder = match progress.phase {
+        FlashPhase::Backup => progress.dir.clone(),
+        _ => format!("{}: {}", progress.dir, progress.status),
+    };
+    eprint!(
+        "\r\x1b[K[{}{}] {} {}/{} folders, {:.1} KB transferred ({})",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        progress.phase,
+        progress.done,
+        progress.total,
+        progress.bytes as f64 / 1024.0,
+        folder
+    );
+    let _ = std::io::stderr().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_info() {
+        let info = parse_device_info("480x320,5,3,8").unwrap();
+        assert_eq!(info.button_size, 89);
+        assert_eq!(info.status_bar_height, 29);
+    }
+
+    #[test]
+    fn rejects_device_info_that_does_not_fit() {
+        assert!(parse_device_info("480x320,0,3,8").is_none());
+        assert!(parse_device_info("480x320,5,3,200").is_none());
+        assert!(parse_device_info("480x320,100000,1,100000").is_none());
+        assert!(parse_device_info("4294967295x320,1,100000,100000").is_none());
+        assert!(parse_device_info("480x320,5,3").is_none());
+    }
+
+    #[test]
+    fn back_buttons_go_in_subfolders_only() {
+        let mut config: Config = serde_json::from_value(json!({
+            "buttons": {
+                "/default/0": { "label": "Top" },
+                "/default/0/1": { "label": "Nested" },
+                "/default/0/14": { "label": "Replaced" },
+            },
+            "back_button": { "label": "Back" }
+        }))
+        .unwrap();
+        let info = parse_device_info("480x320,5,3,8").unwrap();
+
+        add_back_buttons(&mut config, &info);
+        let buttons = config.buttons.unwrap();
+        assert_eq!(buttons.len(), 3);
+        assert!(!buttons.contains_key("/default/14"));
+        let back = &buttons["/default/0/14"];
+        assert_eq!(back.label.as_deref(), Some("Back"));
+        assert!(matches!(&back.icon, Some(IconSource::Embedded(icon)) if icon == BACK_ICON));
+    }
 }
@@ -0,0 +1,275 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine as _};
+use image::ImageFormat;
+use macro_deck_driver::{DeviceInfo, MacroDeck, NodeKind};
+use serde_json::{json, Value};
+
+use super::client;
+use super::flash::{aio_path, forget_manifest, request_device_info};
+use super::models::{FsEntry, FsRequest};
+use super::profile::{load_active_profile, request_active_profile, switch_profile};
+use super::state::device_state_dir;
+
+/// Entries under `path`, parents before their children. Only the direct
+/// children unless `recursive`.
+fn list(deck: &MacroDeck, path: &str, recursive: bool) -> Result<Vec<FsEntry>, String> {
+    let tree = deck
+        .get_tree()
+        .map_err(|e| format!("Failed to list directory: {}", e))?;
+
+    let root = Path::new(path);
+    let entries = tree
+        .walk()
+        .into_iter()
+        .filter(|(entry, _)| {
+            let entry = Path::new(entry);
+            entry != root && entry.starts_with(root) && (recursive || entry.parent() == Some(root))
+        })
+        .map(|(entry, node)| FsEntry {
+            path: entry,
+            kind: node.kind,
+            size: node.size,
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+/// The file an image pushed to `path` is written to. The firmware only shows
+/// JPEG images, so a folder gets its `aio.jpg` and any other path without
+/// `.jpg` the image of that button, `/default/0.jpg` for `/default/0`.
+fn push_path(deck: &MacroDeck, path: &str) -> Result<String, String> {
+    if path.ends_with(".jpg") {
+        return Ok(path.to_string());
+    }
+
+    let tree = deck
+        .get_tree()
+        .map_err(|e| format!("Failed to list directory: {}", e))?;
+    match tree.get(path) {
+        Some(node) if node.kind == NodeKind::Folder => Ok(aio_path(path)),
+        _ => Ok(format!("{}.jpg", path.trim_end_matches('/'))),
+    }
+}
+
+/// Run a filesystem request against the device.
+pub fn handle(deck: &MacroDeck, request: FsRequest) -> Result<Value, String> {
+    match request {
+        FsRequest::Ls { path, recursive } => {
+            let entries = list(deck, &path, recursive)?;
+            Ok(json!(entries))
+        }
+        FsRequest::Mkdir { path } => {
+            deck.create_folder(&path)
+                .map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            Ok(Value::Null)
+        }
+        FsRequest::Rm { path, recursive } => {
+            let tree = deck
+                .get_tree()
+                .map_err(|e| format!("Failed to list directory: {}", e))?;
+            let result = match tree.get(&path) {
+                None => return Err(format!("{} does not exist", path)),
+                Some(node) if node.kind == NodeKind::File => deck.remove_icon(&path),
+                Some(node) if !recursive && !node.children.is_empty() => {
+                    return Err(format!("{} is not empty, use -r to remove it", path));
+                }
+                Some(_) => deck.remove_folder(&path),
+            };
+            result.map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+            Ok(Value::Null)
+        }
+        FsRequest::Push { path, image } => {
+            let path = push_path(deck, &path)?;
+            let data = general_purpose::STANDARD
+                .decode(image)
+                .map_err(|_| "Failed to decode image".to_string())?;
+
+            // JPEG files are written as they are, so a pulled file can be
+            // pushed back unchanged
+            let result = match image::guess_format(&data) {
+                Ok(ImageFormat::Jpeg) => deck.write_file(&path, &data),
+                _ => {
+                    let image = image::load_from_memory(&data)
+                        .map_err(|_| "Failed to decode image".to_string())?;
+                    deck.set_icon(&path, image).map(|_| ())
+                }
+            };
+            result.map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            Ok(json!(path))
+        }
+        FsRequest::Pull { path } => {
+            let data = deck
+                .read_file(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            Ok(json!(general_purpose::STANDARD.encode(data)))
+        }
+    }
+}
+
+fn request(tcp_port: &str, request: &FsRequest) -> Result<Value, String> {
+    let value = client::request(tcp_port, "fs", Some(json!(request)))?;
+    Ok(value.get("result").cloned().unwrap_or_default())
+}
+
+fn print_tree(root: &str, entries: &[FsEntry]) {
+    println!("{}", root);
+
+    let root = Path::new(root);
+    for entry in entries {
+        let path = Path::new(&entry.path);
+        let depth = path
+            .strip_prefix(root)
+            .map_or(0, |relative| relative.components().count());
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        println!("{}{}", "  ".repeat(depth), name);
+    }
+}
+
+/// A device reached through the driver, or directly on its serial port.
+pub enum Device {
+    Driver(String),
+    Direct(MacroDeck, String),
+}
+
+impl Device {
+    pub fn connect(tcp_port: Option<String>, port: Option<String>) -> Result<Device, String> {
+        match port {
+            Some(port) => MacroDeck::new(&port)
+                .map(|deck| Device::Direct(deck, port.clone()))
+                .map_err(|e| format!("Failed to open {}: {}", port, e)),
+            None => Ok(Device::Driver(tcp_port.unwrap_or("8964".to_string()))),
+        }
+    }
+
+    pub fn run(&self, fs_request: FsRequest) -> Result<Value, String> {
+        match self {
+            Device::Driver(tcp_port) => request(tcp_port, &fs_request),
+            Device::Direct(deck, port) => {
+                let modifies = fs_request.modifies();
+                let result = handle(deck, fs_request);
+                if modifies {
+                    forget_manifest(&device_state_dir(port));
+                }
+
+                result
+            }
+        }
+    }
+
+    /// The profile shown on the device, as saved in the state directory.
+    pub fn active_profile(&self) -> Result<Option<String>, String> {
+        match self {
+            Device::Driver(tcp_port) => request_active_profile(tcp_port),
+            Device::Direct(_, port) => Ok(load_active_profile(&device_state_dir(port))),
+        }
+    }
+
+    pub fn switch_profile(&self, name: &str) -> Result<(), String> {
+        match self {
+            Device::Driver(tcp_port) => {
+                client::request(tcp_port, "switchProfile", Some(json!(name))).map(|_| ())
+            }
+            Device::Direct(deck, port) => switch_profile(deck, &device_state_dir(port), name),
+        }
+    }
+
+    pub fn info(&self) -> Result<DeviceInfo, String> {
+        match self {
+            Device::Driver(tcp_port) => request_device_info(tcp_port).ok_or(format!(
+                "Failed to get device info from TCP port {}",
+                tcp_port
+            )),
+            Device::Direct(deck, _) => deck
+                .get_info()
+                .map_err(|e| format!("Failed to get device info: {}", e)),
+        }
+    }
+}
+
+/// Run a filesystem request through the driver, or directly on `port`.
+/// Exits on failure.
+fn run(tcp_port: Option<String>, port: Option<String>, fs_request: FsRequest) -> Value {
+    let result = Device::connect(tcp_port, port).and_then(|device| device.run(fs_request));
+
+    match result {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn ls(tcp_port: Option<String>, port: Option<String>, path: Option<String>, tree: bool) {
+    let path = path.unwrap_or("/".to_string());
+    let result = run(
+        tcp_port,
+        port,
+        FsRequest::Ls {
+            path: path.clone(),
+            recursive: tree,
+        },
+    );
+
+    let entries: Vec<FsEntry> = serde_json::from_value(result).unwrap_or_default();
+    if tree {
+        print_tree(&path, &entries);
+    } else {
+        for entry in entries {
+            println!("{}", entry.path);
+        }
+    }
+}
+
+pub fn mkdir(tcp_port: Option<String>, port: Option<String>, path: String) {
+    run(tcp_port, port, FsRequest::Mkdir { path });
+}
+
+pub fn rm(tcp_port: Option<String>, port: Option<String>, path: String, recursive: bool) {
+    run(tcp_port, port, FsRequest::Rm { path, recursive });
+}
+
+pub fn push(tcp_port: Option<String>, port: Option<String>, local: String, remote: String) {
+    let data = match std::fs::read(&local) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", local, e);
+            std::process::exit(1);
+        }
+    };
+
+    let written = run(
+        tcp_port,
+        port,
+        FsRequest::Push {
+            path: remote.clone(),
+            image: general_purpose::STANDARD.encode(data),
+        },
+    );
+    // Older drivers do not say where the image went
+    println!("Wrote {}", written.as_str().unwrap_or(&remote));
+}
+
+pub fn pull(tcp_port: Option<String>, port: Option<String>, remote: String, local: String) {
+    let result = run(tcp_port, port, FsRequest::Pull { path: remote });
+
+    // The file is saved as stored on the device
+    let data = result
+        .as_str()
+        .and_then(|encoded| general_purpose::STANDARD.decode(encoded).ok());
+    let result = match data {
+        Some(data) => std::fs::write(&local, data).map_err(|e| e.to_string()),
+        None => Err("Failed to decode file".to_string()),
+    };
+
+    match result {
+        Ok(()) => println!("Wrote {}", local),
+        Err(e) => {
+            eprintln!("Failed to write {}: {}", local, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,7 +1,19 @@
+pub mod actions;
 pub mod background_start;
+pub mod backup;
+pub mod client;
+pub mod dispatch;
 pub mod flash;
+pub mod fs;
 pub mod list;
 pub mod models;
+pub mod profile;
+pub mod render;
+pub mod rules;
+pub mod screenshot;
+pub mod sequence;
 pub mod start;
+pub mod state;
 pub mod stop;
+pub mod toggle;
 pub mod tools;
@@ -1,6 +1,9 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, path::PathBuf};
 
-use serde::{Deserialize, Serialize};
+use clap::ValueEnum;
+use log::warn;
+use macro_deck_driver::NodeKind;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -10,15 +13,833 @@ pub struct Message {
     pub value: Option<Value>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FlashOptions {
+    /// Upload every folder, even the unchanged ones
+    #[serde(default)]
+    pub full: bool,
+    /// Read the uploaded images back and upload mismatches again
+    #[serde(default)]
+    pub verify: bool,
+    /// Overrides the layout of the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layout: Option<IconLayout>,
+}
+
+/// Value of the `flash` TCP message
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FlashRequest {
+    pub config_path: Option<String>,
+    #[serde(flatten)]
+    pub options: FlashOptions,
+}
+
+/// Value of the `fs` TCP message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum FsRequest {
+    Ls {
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    Mkdir {
+        path: String,
+    },
+    Rm {
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    Push {
+        path: String,
+        /// Base64 encoded image
+        image: String,
+    },
+    Pull {
+        path: String,
+    },
+}
+
+impl FsRequest {
+    /// Whether the request changes the files on the device
+    pub fn modifies(&self) -> bool {
+        !matches!(self, FsRequest::Ls { .. } | FsRequest::Pull { .. })
+    }
+}
+
+/// A folder or file listed by the `ls` filesystem request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FsEntry {
+    pub path: String,
+    pub kind: NodeKind,
+    /// Size in bytes of a file, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// Value of the `setIcon` TCP message
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetIconRequest {
+    /// Path of the button, such as `/default/0`
+    pub button: String,
+    pub icon: IconSource,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale to fit inside the button, keeping the aspect ratio (letterbox)
+    Contain,
+    /// Scale to fill the button, keeping the aspect ratio (crop)
+    Cover,
+    /// Scale to the button size, ignoring the aspect ratio
+    Stretch,
+    /// Keep the native size, centered
+    None,
+}
+
+/// How the button images of a folder are stored on the device
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IconLayout {
+    /// One `aio.jpg` per folder holding every button
+    #[default]
+    Aio,
+    /// One `<index>.jpg` per button, so a button can be updated on its own
+    Buttons,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelPosition {
+    Top,
+    Center,
+    Bottom,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IconStyle {
+    /// Color drawn behind the icon, e.g. `#202020` or `#20202080`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    /// Space in pixels between the icon and the edge of the button
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub padding: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corner_radius: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub border_color: Option<String>,
+    /// How the icon is scaled into the button, defaults to `contain`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fit: Option<Fit>,
+    /// Color replacing every pixel of the icon, keeping its transparency.
+    /// Useful for monochrome icon sets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_color: Option<String>,
+    /// Point of the icon kept in view when it is cropped, as `[x, y]` in the
+    /// range 0 to 1. Defaults to the center.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal_point: Option<[f32; 2]>,
+}
+
+impl IconStyle {
+    /// Fill the unset fields of this style from `fallback`.
+    pub fn merge(&self, fallback: &IconStyle) -> IconStyle {
+        IconStyle {
+            background: self.background.clone().or(fallback.background.clone()),
+            padding: self.padding.or(fallback.padding),
+            corner_radius: self.corner_radius.or(fallback.corner_radius),
+            border_width: self.border_width.or(fallback.border_width),
+            border_color: self.border_color.clone().or(fallback.border_color.clone()),
+            fit: self.fit.or(fallback.fit),
+            icon_color: self.icon_color.clone().or(fallback.icon_color.clone()),
+            focal_point: self.focal_point.or(fallback.focal_point),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum IconSource {
+    /// Base64 encoded image or inline SVG markup
+    Embedded(String),
+    /// Image file, relative to the config file
+    Path { path: String },
+    /// `file://` URL of an image
+    Url { url: String },
+}
+
+/// What a button does when pressed, tagged by `action`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum ButtonAction {
+    /// Run a program
+    Exec {
+        command: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        args: Option<Vec<String>>,
+    },
+    /// Run a command line with `sh -c` (`cmd /C` on Windows)
+    Shell { command: String },
+    /// Open a URL with the default application
+    OpenUrl { url: String },
+    /// Send an HTTP request, failing on error statuses
+    HttpRequest {
+        /// Defaults to `GET`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        method: Option<String>,
+        url: String,
+        /// Sent as is when a string, as JSON otherwise
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body: Option<Value>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
+    },
+    /// Send data to a TCP address such as `127.0.0.1:4455`
+    TcpSend { address: String, data: String },
+    /// Write text to a file, replacing its content unless `append`
+    WriteFile {
+        path: String,
+        content: String,
+        #[serde(default)]
+        append: bool,
+    },
+    /// Show another profile on the device
+    SwitchProfile { profile: String },
+    /// Run steps one after another, stopping at the first failure
+    Sequence { steps: Vec<SequenceStep> },
+}
+
+const HTTP_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+fn not_empty(field: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        Err(format!("{} is empty", field))
+    } else {
+        Ok(())
+    }
+}
+
+impl ButtonAction {
+    /// Check what the types alone do not, such as URLs and addresses.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ButtonAction::Exec { command, .. } | ButtonAction::Shell { command } => {
+                not_empty("command", command)
+            }
+            ButtonAction::OpenUrl { url } => {
+                not_empty("url", url)?;
+                if !url.contains(':') {
+                    return Err(format!("{} is not a URL", url));
+                }
+                Ok(())
+            }
+            ButtonAction::HttpRequest { method, url, .. } => {
+                if let Some(method) = method {
+                    if !HTTP_METHODS.contains(&method.to_uppercase().as_str()) {
+                        return Err(format!("Unknown HTTP method: {}", method));
+                    }
+                }
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(format!("{} is not an HTTP URL", url));
+                }
+                Ok(())
+            }
+            ButtonAction::TcpSend { address, .. } => match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+                _ => Err(format!("{} is not a host:port address", address)),
+            },
+            ButtonAction::WriteFile { path, .. } => not_empty("path", path),
+            ButtonAction::SwitchProfile { profile } => not_empty("profile", profile),
+            ButtonAction::Sequence { steps } => {
+                if steps.is_empty() {
+                    return Err("The sequence has no steps".to_string());
+                }
+                validate_steps(steps)
+            }
+        }
+    }
+}
+
+fn validate_steps(steps: &[SequenceStep]) -> Result<(), String> {
+    for (idx, step) in steps.iter().enumerate() {
+        step.validate()
+            .map_err(|e| format!("Step {}: {}", idx + 1, e))?;
+    }
+
+    Ok(())
+}
+
+/// Actions opening folders, which the firmware does not let the driver do
+const NAVIGATION_ACTIONS: [&str; 3] = ["openFolder", "back", "home"];
+
+/// The action among the fields of a button or a step, if it has one
+fn action_from_fields(
+    mut fields: serde_json::Map<String, Value>,
+) -> Result<Option<ButtonAction>, serde_json::Error> {
+    if !fields.contains_key("action") {
+        // Older configs only have `command` and `args`
+        if !matches!(fields.get("command"), Some(Value::String(_))) {
+            return Ok(None);
+        }
+        fields.insert("action".to_string(), Value::from("exec"));
+    }
+
+    // The firmware has no command showing a subfolder, so these cannot work yet
+    if let Some(Value::String(action)) = fields.get("action") {
+        if NAVIGATION_ACTIONS.contains(&action.as_str()) {
+            return Err(serde_json::Error::custom(format!(
+                "The {} action is not supported, the device navigates its folders itself",
+                action
+            )));
+        }
+    }
+
+    serde_json::from_value(Value::Object(fields)).map(Some)
+}
+
+/// A flattened `Option` turns every error into `None`, so only parse the
+/// action when the button has one and report what is wrong with it.
+fn deserialize_action<'de, D>(deserializer: D) -> Result<Option<ButtonAction>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fields = serde_json::Map::<String, Value>::deserialize(deserializer)?;
+    action_from_fields(fields).map_err(D::Error::custom)
+}
+
+/// One exit code or a list of them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ExitCodes {
+    One(i32),
+    Any(Vec<i32>),
+}
+
+impl ExitCodes {
+    pub fn contains(&self, code: i32) -> bool {
+        match self {
+            ExitCodes::One(expected) => *expected == code,
+            ExitCodes::Any(expected) => expected.contains(&code),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum StepKind {
+    /// Wait before the next step
+    Delay {
+        delay_ms: u64,
+    },
+    /// Run the steps at the same time, the group fails if any of them fails
+    Parallel {
+        parallel: Vec<SequenceStep>,
+    },
+    Action(ButtonAction),
+}
+
+/// Step of a `sequence`: an action, a `delay_ms` or a `parallel` group
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "serde_json::Map<String, Value>")]
+pub struct SequenceStep {
+    #[serde(flatten)]
+    pub kind: StepKind,
+    /// Go on with the next step when this one fails
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub continue_on_error: bool,
+    /// Only run the step when the last command exited with one of these
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub if_exit_code: Option<ExitCodes>,
+}
+
+impl TryFrom<serde_json::Map<String, Value>> for SequenceStep {
+    type Error = String;
+
+    fn try_from(mut fields: serde_json::Map<String, Value>) -> Result<Self, Self::Error> {
+        let continue_on_error = match fields.remove("continue_on_error") {
+            Some(value) => serde_json::from_value(value)
+                .map_err(|e| format!("Invalid continue_on_error: {}", e))?,
+            None => false,
+        };
+        let if_exit_code = match fields.remove("if_exit_code") {
+            Some(value) => Some(
+                serde_json::from_value(value)
+                    .map_err(|_| "if_exit_code has to be a number or a list of numbers")?,
+            ),
+            None => None,
+        };
+
+        let kind = if let Some(delay_ms) = fields.remove("delay_ms") {
+            StepKind::Delay {
+                delay_ms: serde_json::from_value(delay_ms)
+                    .map_err(|e| format!("Invalid delay_ms: {}", e))?,
+            }
+        } else if let Some(parallel) = fields.remove("parallel") {
+            StepKind::Parallel {
+                parallel: serde_json::from_value(parallel)
+                    .map_err(|e| format!("Invalid parallel group: {}", e))?,
+            }
+        } else {
+            match action_from_fields(fields).map_err(|e| e.to_string())? {
+                Some(action) => StepKind::Action(action),
+                None => return Err("A step needs an action, delay_ms or parallel".to_string()),
+            }
+        };
+
+        Ok(SequenceStep {
+            kind,
+            continue_on_error,
+            if_exit_code,
+        })
+    }
+}
+
+impl SequenceStep {
+    /// Whether `if_exit_code` lets the step run after a command exiting with
+    /// `last_exit_code`, `None` when no command ran yet.
+    pub fn runs_after(&self, last_exit_code: Option<i32>) -> bool {
+        match &self.if_exit_code {
+            Some(codes) => last_exit_code.is_some_and(|code| codes.contains(code)),
+            None => true,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match &self.kind {
+            StepKind::Delay { .. } => Ok(()),
+            StepKind::Parallel { parallel } => {
+                if parallel.is_empty() {
+                    return Err("The parallel group has no steps".to_string());
+                }
+                validate_steps(parallel)
+            }
+            StepKind::Action(action) => action.validate(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ButtonConfig {
-    pub command: Option<String>,
-    pub args: Option<Vec<String>>,
-    pub icon: Option<String>,
+    /// What the button does, a `command` without `action` runs the program
+    #[serde(
+        flatten,
+        default,
+        deserialize_with = "deserialize_action",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub action: Option<ButtonAction>,
+    pub icon: Option<IconSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<IconStyle>,
+    /// Text drawn on the button, use `\n` for multiple lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Font size in pixels, shrunk when the label is wider than the button
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font_size: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_color: Option<String>,
+    /// Defaults to `bottom` over an icon and `center` without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label_position: Option<LabelPosition>,
+    /// Two states with their own icon and action, replacing `action`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toggle: Option<ToggleConfig>,
+    /// What a press does while the last one is still running (default:
+    /// parallel)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<Concurrency>,
+    /// Overrides `timeout_ms` of `handlers`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// What a press does while the handler of the button is still running
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Concurrency {
+    /// Run the presses at the same time
+    #[default]
+    Parallel,
+    /// Run the presses one after the other
+    Queue,
+    /// Drop the press
+    IgnoreWhileRunning,
+    /// Stop the running handler and start again
+    Restart,
+}
+
+/// Worker threads running the button handlers
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HandlersConfig {
+    /// Handlers running at the same time, more presses wait (default: 4)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+    /// Stop handlers running longer, killing their commands (default: none)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// One state of a toggle button
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ToggleVariant {
+    /// Runs when the button switches to this state
+    #[serde(
+        flatten,
+        default,
+        deserialize_with = "deserialize_action",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub action: Option<ButtonAction>,
+    /// Shown in this state, defaults to the icon of the button
+    pub icon: Option<IconSource>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ToggleConfig {
+    pub on: ToggleVariant,
+    pub off: ToggleVariant,
+    /// Shell command exiting with 0 while the toggle is on, run periodically
+    /// to pick up changes made elsewhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_command: Option<String>,
+    /// How often `state_command` runs (default: 5000)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_interval_ms: Option<u64>,
+}
+
+impl ToggleConfig {
+    pub fn variant(&self, on: bool) -> &ToggleVariant {
+        if on {
+            &self.on
+        } else {
+            &self.off
+        }
+    }
+
+    fn validate(&self, button: &ButtonConfig) -> Result<(), String> {
+        if button.action.is_some() {
+            return Err("A toggle has its actions in on and off".to_string());
+        }
+        for (name, variant) in [("on", &self.on), ("off", &self.off)] {
+            if let Some(action) = &variant.action {
+                action.validate().map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+        if let Some(command) = &self.state_command {
+            not_empty("state_command", command)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FolderConfig {
+    /// Color filling the whole folder, including the gaps between buttons
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    /// Image scaled to cover the whole folder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_image: Option<IconSource>,
+}
+
+/// Back button added to every subfolder when flashing
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BackButtonConfig {
+    /// Index of the reserved button (default: the last one)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<usize>,
+    /// Replaces the standard back icon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<IconSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<IconStyle>,
+}
+
+/// Settings of one device, overriding the ones of the config
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DeviceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layout: Option<IconLayout>,
+}
+
+/// Buttons and folders of one profile, with paths relative to the profile
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProfileConfig {
+    pub buttons: Option<HashMap<String, ButtonConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folders: Option<HashMap<String, FolderConfig>>,
+}
+
+/// Condition of a profile rule, such as `{"time": "09:00-17:00"}`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleCondition {
+    /// Local time range as `HH:MM-HH:MM`, may wrap past midnight
+    Time(String),
+    /// Days of the week, such as `["mon", "tue"]`
+    Days(Vec<String>),
+    /// Name of this machine
+    Hostname(String),
+    /// Name of a running process
+    Process(String),
+    /// Value pushed with the `setValue` TCP message. Any value matches
+    /// without `equals`.
+    Value {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        equals: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileRule {
+    pub profile: String,
+    /// Every condition has to hold, an empty list always matches
+    #[serde(default)]
+    pub when: Vec<RuleCondition>,
+}
+
+/// Rules picking the profile of the device
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AutoProfileConfig {
+    /// The first matching rule wins
+    pub rules: Vec<ProfileRule>,
+    /// Milliseconds between two evaluations (default: 1000)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<u64>,
+    /// Milliseconds a new profile has to keep winning before switching to it
+    /// (default: 0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+}
+
+/// Value of the `setValue` TCP message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetValueRequest {
+    pub name: String,
+    /// Removes the value when missing or null
+    pub value: Option<Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
     pub buttons: Option<HashMap<String, ButtonConfig>>,
     pub status: Option<ButtonConfig>,
+    /// Default style for every button
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<IconStyle>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folders: Option<HashMap<String, FolderConfig>>,
+    /// How the images are stored on the device (default: aio)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layout: Option<IconLayout>,
+    /// Settings of each device, by serial port
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<HashMap<String, DeviceConfig>>,
+    /// Profiles by name, each stored in its own top level folder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<HashMap<String, ProfileConfig>>,
+    /// Reserve a button in every subfolder for a back icon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub back_button: Option<BackButtonConfig>,
+    /// Rules switching between the profiles automatically
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_profile: Option<AutoProfileConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handlers: Option<HandlersConfig>,
+    /// Directory of the config file, icon paths are relative to it
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+/// Whether two serial port paths name the same port. Stable names such as
+/// `/dev/serial/by-id/...` are links to the port.
+fn same_port(a: &str, b: &str) -> bool {
+    a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Path of `key` inside the folder of `profile`
+fn profile_path(profile: &str, key: &str) -> String {
+    let key = key.trim_matches('/');
+    if key.is_empty() {
+        format!("/{}", profile)
+    } else {
+        format!("/{}/{}", profile, key)
+    }
+}
+
+impl Config {
+    /// The layout set for the device on `port` in `devices`, if any.
+    pub fn device_layout(&self, port: &str) -> Option<IconLayout> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|(name, _)| same_port(name, port))
+            .and_then(|(_, device)| device.layout)
+    }
+
+    /// Check the action and the toggle of every button.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.handlers.as_ref().and_then(|handlers| handlers.workers) == Some(0) {
+            return Err("handlers: At least one worker is needed".to_string());
+        }
+
+        let mut buttons: Vec<_> = self.buttons.iter().flatten().collect();
+        buttons.sort_by_key(|(key, _)| key.as_str());
+
+        for (key, button) in buttons {
+            if let Some(action) = &button.action {
+                action.validate().map_err(|e| format!("{}: {}", key, e))?;
+            }
+            if let Some(toggle) = &button.toggle {
+                toggle
+                    .validate(button)
+                    .map_err(|e| format!("{}: {}", key, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give both states of every toggle an icon, falling back to the icon of
+    /// the button, and show the `off` one until the state is known.
+    pub fn expand_toggles(&mut self) {
+        for button in self
+            .buttons
+            .iter_mut()
+            .flat_map(|buttons| buttons.values_mut())
+        {
+            let toggle = match &mut button.toggle {
+                Some(toggle) => toggle,
+                None => continue,
+            };
+
+            for variant in [&mut toggle.on, &mut toggle.off] {
+                if variant.icon.is_none() {
+                    variant.icon = button.icon.clone();
+                }
+            }
+            button.icon = toggle.off.icon.clone();
+        }
+    }
+
+    /// Move the buttons and folders of every profile to `buttons` and
+    /// `folders`, under the folder of the profile.
+    pub fn expand_profiles(&mut self) {
+        let profiles = match self.profiles.take() {
+            Some(profiles) => profiles,
+            None => return,
+        };
+
+        for (name, profile) in profiles {
+            let buttons = self.buttons.get_or_insert_with(HashMap::new);
+            for (key, button) in profile.buttons.unwrap_or_default() {
+                let path = profile_path(&name, &key);
+                if buttons.insert(path.clone(), button).is_some() {
+                    warn!("Button {} is defined twice, using profile {}", path, name);
+                }
+            }
+
+            let folders = self.folders.get_or_insert_with(HashMap::new);
+            for (key, folder) in profile.folders.unwrap_or_default() {
+                let path = profile_path(&name, &key);
+                if folders.insert(path.clone(), folder).is_some() {
+                    warn!("Folder {} is defined twice, using profile {}", path, name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn step(value: Value) -> Result<SequenceStep, String> {
+        match value {
+            Value::Object(fields) => SequenceStep::try_from(fields),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn steps_parse_by_their_fields() {
+        let delay = step(json!({ "delay_ms": 500, "continue_on_error": true })).unwrap();
+        assert!(matches!(delay.kind, StepKind::Delay { delay_ms: 500 }));
+        assert!(delay.continue_on_error);
+        assert!(delay.if_exit_code.is_none());
+
+        let parallel = step(json!({
+            "parallel": [{ "delay_ms": 1 }, { "command": "true" }]
+        }))
+        .unwrap();
+        match parallel.kind {
+            StepKind::Parallel { parallel } => {
+                assert_eq!(parallel.len(), 2);
+                assert!(matches!(
+                    &parallel[1].kind,
+                    StepKind::Action(ButtonAction::Exec { command, .. }) if command == "true"
+                ));
+            }
+            kind => panic!("Not a parallel group: {:?}", kind),
+        }
+
+        let action =
+            step(json!({ "action": "switchProfile", "profile": "work", "if_exit_code": [0, 2] }))
+                .unwrap();
+        assert!(matches!(
+            &action.kind,
+            StepKind::Action(ButtonAction::SwitchProfile { profile }) if profile == "work"
+        ));
+        assert!(!action.continue_on_error);
+    }
+
+    #[test]
+    fn invalid_steps_are_reported() {
+        assert_eq!(
+            step(json!({ "label": "nothing" })).err().unwrap(),
+            "A step needs an action, delay_ms or parallel"
+        );
+        assert_eq!(
+            step(json!({ "delay_ms": 1, "if_exit_code": "0" }))
+                .err()
+                .unwrap(),
+            "if_exit_code has to be a number or a list of numbers"
+        );
+        assert!(step(json!({ "delay_ms": -1 }))
+            .err()
+            .unwrap()
+            .starts_with("Invalid delay_ms"));
+        assert!(step(json!({ "action": "teleport" })).is_err());
+    }
+
+    #[test]
+    fn if_exit_code_skips_other_codes() {
+        let always = step(json!({ "delay_ms": 1 })).unwrap();
+        assert!(always.runs_after(None));
+        assert!(always.runs_after(Some(1)));
+
+        let one = step(json!({ "delay_ms": 1, "if_exit_code": 1 })).unwrap();
+        assert!(one.runs_after(Some(1)));
+        assert!(!one.runs_after(Some(0)));
+        // Nothing exited yet, so no code can match
+        assert!(!one.runs_after(None));
+
+        let any = step(json!({ "delay_ms": 1, "if_exit_code": [0, 2] })).unwrap();
+        assert!(any.runs_after(Some(0)));
+        assert!(any.runs_after(Some(2)));
+        assert!(!any.runs_after(Some(1)));
+    }
 }
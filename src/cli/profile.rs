@@ -0,0 +1,147 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use log::{info, warn};
+use macro_deck_driver::MacroDeck;
+use serde_json::{json, Value};
+
+use super::client::request;
+use super::state::device_state_dir;
+
+/// File in the state directory holding the name of the active profile
+const ACTIVE_PROFILE: &str = "profile";
+/// Present while a manual switch overrides the rules
+const OVERRIDE: &str = "profile-override";
+
+pub fn load_active_profile(state_dir: &Path) -> Option<String> {
+    fs::read_to_string(state_dir.join(ACTIVE_PROFILE))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Top level folder of the active profile, `/default` before any switch.
+pub fn active_profile_folder(state_dir: &Path) -> String {
+    format!(
+        "/{}",
+        load_active_profile(state_dir).unwrap_or("default".to_string())
+    )
+}
+
+fn save_active_profile(state_dir: &Path, name: &str) {
+    let result = fs::create_dir_all(state_dir)
+        .and_then(|()| fs::write(state_dir.join(ACTIVE_PROFILE), name));
+    if let Err(e) = result {
+        warn!("Failed to save the active profile: {}", e);
+    }
+}
+
+/// Whether the profile was switched by hand since the override was cleared
+pub fn has_override(state_dir: &Path) -> bool {
+    state_dir.join(OVERRIDE).exists()
+}
+
+/// Let the rules pick the profile again.
+pub fn clear_override(state_dir: &Path) -> Result<(), String> {
+    match fs::remove_file(state_dir.join(OVERRIDE)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(format!("Failed to clear the override: {}", e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Show the profile `name` on the device and remember it, without touching
+/// the override.
+pub fn apply_profile(deck: &MacroDeck, state_dir: &Path, name: &str) -> Result<(), String> {
+    let name = name.trim_matches('/');
+    let tree = deck
+        .get_tree()
+        .map_err(|e| format!("Failed to list directory: {}", e))?;
+    if name.is_empty() || name.contains('/') || !tree.contains(&format!("/{}", name)) {
+        return Err(format!("Profile {} does not exist on the device", name));
+    }
+
+    deck.set_profile(name)
+        .map_err(|e| format!("Failed to switch to {}: {}", name, e))?;
+    save_active_profile(state_dir, name);
+
+    Ok(())
+}
+
+/// Switch to the profile `name` by hand. The rules leave it alone until the
+/// override is cleared.
+pub fn switch_profile(deck: &MacroDeck, state_dir: &Path, name: &str) -> Result<(), String> {
+    apply_profile(deck, state_dir, name)?;
+
+    let result =
+        fs::create_dir_all(state_dir).and_then(|()| fs::write(state_dir.join(OVERRIDE), ""));
+    if let Err(e) = result {
+        warn!("Failed to save the override: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Switch back to the profile that was active before the driver stopped.
+pub fn restore_profile(deck: &MacroDeck, state_dir: &Path) {
+    let name = match load_active_profile(state_dir) {
+        Some(name) => name,
+        None => return,
+    };
+
+    match apply_profile(deck, state_dir, &name) {
+        Ok(()) => info!("Restored profile {}", name),
+        Err(e) => warn!("Failed to restore profile: {}", e),
+    }
+}
+
+/// The active profile of the device driven on `tcp_port`.
+pub fn request_active_profile(tcp_port: &str) -> Result<Option<String>, String> {
+    let value = request(tcp_port, "getProfile", None)?;
+    Ok(value.as_str().map(str::to_string))
+}
+
+pub fn switch(tcp_port: Option<String>, port: Option<String>, name: String) {
+    let result = match port {
+        Some(port) => match MacroDeck::new(&port) {
+            Ok(deck) => switch_profile(&deck, &device_state_dir(&port), &name),
+            Err(e) => Err(format!("Failed to open {}: {}", port, e)),
+        },
+        None => request(
+            &tcp_port.unwrap_or("8964".to_string()),
+            "switchProfile",
+            Some(json!(name)),
+        )
+        .map(|_| ()),
+    };
+
+    match result {
+        Ok(()) => println!("Switched to profile {}", name),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn auto(tcp_port: Option<String>, port: Option<String>) {
+    let result = match port {
+        Some(port) => clear_override(&device_state_dir(&port)),
+        None => request(
+            &tcp_port.unwrap_or("8964".to_string()),
+            "autoProfile",
+            Some(Value::Null),
+        )
+        .map(|_| ()),
+    };
+
+    match result {
+        Ok(()) => println!("The rules pick the profile again"),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use std::{fs, path::Path};
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use base64::{engine::general_purpose, Engine as _};
+use image::{imageops, imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+use log::warn;
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{Options, Tree},
+};
+
+use super::models::{ButtonConfig, Fit, IconSource, IconStyle, LabelPosition};
+
+const FONT: &[u8] = include_bytes!("../../assets/fonts/DejaVuSans-Bold.ttf");
+/// Icon of the back buttons added to subfolders
+pub const BACK_ICON: &str = include_str!("../../assets/icons/back.svg");
+
+pub struct Label<'a> {
+    pub text: &'a str,
+    pub font_size: Option<f32>,
+    pub color: Option<&'a str>,
+    pub position: Option<LabelPosition>,
+}
+
+impl<'a> Label<'a> {
+    pub fn from_button(button: &'a ButtonConfig) -> Option<Self> {
+        let text = button.label.as_deref()?;
+        if text.trim().is_empty() {
+            return None;
+        }
+
+        Some(Label {
+            text,
+            font_size: button.font_size,
+            color: button.text_color.as_deref(),
+            position: button.label_position,
+        })
+    }
+}
+
+pub fn parse_color(color: &str) -> Option<Rgba<u8>> {
+    let hex = color.trim();
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let hex: String = match hex.len() {
+        3 | 4 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 | 8 => hex.to_string(),
+        _ => return None,
+    };
+
+    let value = u32::from_str_radix(&hex, 16).ok()?;
+    let value = if hex.len() == 6 {
+        (value << 8) | 0xff
+    } else {
+        value
+    };
+
+    Some(Rgba(value.to_be_bytes()))
+}
+
+fn color_or_warn(color: &str) -> Option<Rgba<u8>> {
+    let parsed = parse_color(color);
+    if parsed.is_none() {
+        warn!("Invalid color: {}", color);
+    }
+
+    parsed
+}
+
+pub enum Icon {
+    Raster(DynamicImage),
+    /// Kept as a tree so it can be rasterized at the final size
+    Vector(Box<Tree>),
+}
+
+impl Icon {
+    pub fn load(data: &[u8]) -> Option<Self> {
+        let start = data.iter().position(|b| !b.is_ascii_whitespace())?;
+
+        // SVG markup or gzip compressed SVGZ
+        if data[start] == b'<' || data.starts_with(&[0x1f, 0x8b]) {
+            return Tree::from_data(data, &Options::default())
+                .ok()
+                .map(|tree| Icon::Vector(Box::new(tree)));
+        }
+
+        image::load_from_memory(data).ok().map(Icon::Raster)
+    }
+
+    fn size(&self) -> (f32, f32) {
+        match self {
+            Icon::Raster(image) => (image.width() as f32, image.height() as f32),
+            Icon::Vector(tree) => (tree.size().width(), tree.size().height()),
+        }
+    }
+}
+
+/// Decode a config icon, either Base64 encoded data or inline SVG markup.
+pub fn decode_icon(encoded: &str) -> Option<Icon> {
+    if encoded.trim_start().starts_with('<') {
+        return Icon::load(encoded.as_bytes());
+    }
+
+    let data = general_purpose::STANDARD.decode(encoded).ok()?;
+    Icon::load(&data)
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = input
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Resolve a config icon, reading files relative to `base_dir`.
+pub fn load_icon(source: &IconSource, base_dir: &Path) -> Option<Icon> {
+    let path = match source {
+        IconSource::Embedded(encoded) => return decode_icon(encoded),
+        IconSource::Path { path } => base_dir.join(path),
+        IconSource::Url { url } => {
+            let path = match url.strip_prefix("file://") {
+                Some(path) => path.strip_prefix("localhost").unwrap_or(path),
+                None => {
+                    warn!("Unsupported icon URL: {}", url);
+                    return None;
+                }
+            };
+
+            base_dir.join(percent_decode(path))
+        }
+    };
+
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Failed to read icon file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    Icon::load(&data)
+}
+
+/// Composite `src` over `dst` with the given opacity (source-over).
+fn blend(dst: &mut Rgba<u8>, src: Rgba<u8>, opacity: f32) {
+    let src_alpha = src[3] as f32 / 255.0 * opacity;
+    let dst_alpha = dst[3] as f32 / 255.0;
+    let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+    if out_alpha <= 0.0 {
+        *dst = Rgba([0, 0, 0, 0]);
+        return;
+    }
+
+    for i in 0..3 {
+        let value =
+            (src[i] as f32 * src_alpha + dst[i] as f32 * dst_alpha * (1.0 - src_alpha)) / out_alpha;
+        dst[i] = value.round() as u8;
+    }
+    dst[3] = (out_alpha * 255.0).round() as u8;
+}
+
+/// Signed distance from the center of pixel (x, y) to the edge of a rounded
+/// square of the given size. Negative inside.
+fn rounded_distance(x: u32, y: u32, size: u32, radius: f32) -> f32 {
+    let half = size as f32 / 2.0;
+    let qx = (x as f32 + 0.5 - half).abs() - (half - radius);
+    let qy = (y as f32 + 0.5 - half).abs() - (half - radius);
+
+    let outside = (qx.max(0.0).powi(2) + qy.max(0.0).powi(2)).sqrt();
+    let inside = qx.max(qy).min(0.0);
+
+    outside + inside - radius
+}
+
+/// Offset of a `length` long image inside a `size` long box. Smaller images
+/// are centered, larger ones are cropped around `focus` (0 to 1).
+fn fit_offset(length: u32, size: u32, focus: f32) -> i64 {
+    if length <= size {
+        return (size - length) as i64 / 2;
+    }
+
+    let max_offset = (length - size) as f32;
+    let offset = (focus.clamp(0.0, 1.0) * length as f32 - size as f32 / 2.0).clamp(0.0, max_offset);
+
+    -(offset.round() as i64)
+}
+
+fn render_svg(tree: &Tree, width: u32, height: u32, transform: Transform) -> RgbaImage {
+    let mut pixmap = match Pixmap::new(width, height) {
+        Some(pixmap) => pixmap,
+        None => return RgbaImage::new(width, height),
+    };
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+
+    let data = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(width, height, data).unwrap_or_else(|| RgbaImage::new(width, height))
+}
+
+fn fit_icon(icon: &Icon, width: u32, height: u32, fit: Fit, focal_point: [f32; 2]) -> RgbaImage {
+    let (icon_width, icon_height) = icon.size();
+    let (icon_width, icon_height) = (icon_width.max(1.0), icon_height.max(1.0));
+    let scale_x = width as f32 / icon_width;
+    let scale_y = height as f32 / icon_height;
+
+    let (scale_x, scale_y) = match fit {
+        Fit::Stretch => (scale_x, scale_y),
+        Fit::None => (1.0, 1.0),
+        Fit::Contain => (scale_x.min(scale_y), scale_x.min(scale_y)),
+        Fit::Cover => (scale_x.max(scale_y), scale_x.max(scale_y)),
+    };
+    let scaled_width = ((icon_width * scale_x).round() as u32).max(1);
+    let scaled_height = ((icon_height * scale_y).round() as u32).max(1);
+
+    let x = fit_offset(scaled_width, width, focal_point[0]);
+    let y = fit_offset(scaled_height, height, focal_point[1]);
+
+    match icon {
+        Icon::Raster(image) => {
+            let scaled = if fit == Fit::None {
+                image.to_rgba8()
+            } else {
+                image
+                    .resize_exact(scaled_width, scaled_height, FilterType::Lanczos3)
+                    .to_rgba8()
+            };
+
+            let mut fitted = RgbaImage::new(width, height);
+            imageops::overlay(&mut fitted, &scaled, x, y);
+
+            fitted
+        }
+        Icon::Vector(tree) => {
+            let transform = Transform::from_row(scale_x, 0.0, 0.0, scale_y, x as f32, y as f32);
+            render_svg(tree, width, height, transform)
+        }
+    }
+}
+
+/// Replace the color of every pixel, keeping its alpha. Meant for monochrome
+/// icon sets.
+fn recolor(image: &mut RgbaImage, color: Rgba<u8>) {
+    for pixel in image.pixels_mut() {
+        let alpha = pixel[3] as u32 * color[3] as u32 / 255;
+        *pixel = Rgba([color[0], color[1], color[2], alpha as u8]);
+    }
+}
+
+fn line_width<F: Font>(font: &impl ScaleFont<F>, line: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+
+    for c in line.chars() {
+        let glyph = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, glyph);
+        }
+        width += font.h_advance(glyph);
+        previous = Some(glyph);
+    }
+
+    width
+}
+
+fn draw_label(cell: &mut RgbaImage, label: &Label, has_icon: bool) {
+    let font = FontRef::try_from_slice(FONT).expect("Embedded font is invalid");
+    let size = cell.width();
+    let margin = size as f32 / 16.0;
+    let max_width = size as f32 - margin * 2.0;
+    let lines: Vec<&str> = label.text.lines().collect();
+
+    // Shrink the font until the widest line fits
+    let mut font_size = label.font_size.unwrap_or(size as f32 / 5.0).max(1.0);
+    let widest = lines
+        .iter()
+        .map(|line| line_width(&font.as_scaled(PxScale::from(font_size)), line))
+        .fold(0.0, f32::max);
+    if widest > max_width {
+        font_size *= max_width / widest;
+    }
+
+    let scaled = font.as_scaled(PxScale::from(font_size));
+    let line_height = scaled.height() + scaled.line_gap();
+    let total_height = line_height * lines.len() as f32 - scaled.line_gap();
+
+    let position = label.position.unwrap_or(if has_icon {
+        LabelPosition::Bottom
+    } else {
+        LabelPosition::Center
+    });
+    let top = match position {
+        LabelPosition::Top => margin,
+        LabelPosition::Center => (size as f32 - total_height) / 2.0,
+        LabelPosition::Bottom => size as f32 - margin - total_height,
+    };
+
+    let color = label
+        .color
+        .and_then(color_or_warn)
+        .unwrap_or(Rgba([255, 255, 255, 255]));
+
+    for (i, line) in lines.iter().enumerate() {
+        let baseline = top + line_height * i as f32 + scaled.ascent();
+        let mut x = (size as f32 - line_width(&scaled, line)) / 2.0;
+        let mut previous = None;
+
+        for c in line.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                x += scaled.kern(previous, id);
+            }
+            previous = Some(id);
+
+            let glyph = id.with_scale_and_position(scaled.scale(), point(x, baseline));
+            x += scaled.h_advance(id);
+
+            let Some(outlined) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+
+            outlined.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+                if px < 0 || py < 0 || px >= size as i64 || py >= size as i64 {
+                    return;
+                }
+
+                blend(cell.get_pixel_mut(px as u32, py as u32), color, coverage);
+            });
+        }
+    }
+}
+
+pub fn render_button(
+    icon: Option<&Icon>,
+    label: Option<&Label>,
+    style: &IconStyle,
+    size: u32,
+) -> RgbaImage {
+    let mut cell = RgbaImage::new(size, size);
+
+    if let Some(background) = style.background.as_deref().and_then(color_or_warn) {
+        for pixel in cell.pixels_mut() {
+            *pixel = background;
+        }
+    }
+
+    if let Some(icon) = icon {
+        let padding = style.padding.unwrap_or(0).min(size.saturating_sub(1) / 2);
+        let inner = size - padding * 2;
+        let mut icon = fit_icon(
+            icon,
+            inner,
+            inner,
+            style.fit.unwrap_or(Fit::Contain),
+            style.focal_point.unwrap_or([0.5, 0.5]),
+        );
+
+        if let Some(color) = style.icon_color.as_deref().and_then(color_or_warn) {
+            recolor(&mut icon, color);
+        }
+
+        imageops::overlay(&mut cell, &icon, padding as i64, padding as i64);
+    }
+
+    if let Some(label) = label {
+        draw_label(&mut cell, label, icon.is_some());
+    }
+
+    let radius = style.corner_radius.unwrap_or(0).min(size / 2) as f32;
+    let border_width = style.border_width.unwrap_or(0) as f32;
+    let border_color = if border_width > 0.0 {
+        style
+            .border_color
+            .as_deref()
+            .map_or(Some(Rgba([255, 255, 255, 255])), color_or_warn)
+    } else {
+        None
+    };
+
+    if radius == 0.0 && border_color.is_none() {
+        return cell;
+    }
+
+    for (x, y, pixel) in cell.enumerate_pixels_mut() {
+        let distance = rounded_distance(x, y, size, radius);
+
+        if let Some(border_color) = border_color {
+            let amount = (distance + border_width + 0.5).clamp(0.0, 1.0);
+            if amount > 0.0 {
+                blend(pixel, border_color, amount);
+            }
+        }
+
+        let coverage = (0.5 - distance).clamp(0.0, 1.0);
+        pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+    }
+
+    cell
+}
+
+/// Create the background of a folder from its color and image.
+pub fn render_background(
+    width: u32,
+    height: u32,
+    color: Option<&str>,
+    image: Option<&Icon>,
+) -> RgbaImage {
+    let color = color
+        .and_then(color_or_warn)
+        .unwrap_or(Rgba([0, 0, 0, 255]));
+    let mut background = RgbaImage::from_pixel(width, height, color);
+
+    if let Some(image) = image {
+        let image = fit_icon(image, width, height, Fit::Cover, [0.5, 0.5]);
+        imageops::overlay(&mut background, &image, 0, 0);
+    }
+
+    background
+}
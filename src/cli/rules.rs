@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, Local, NaiveTime, Weekday};
+use log::{debug, error, info, warn};
+use macro_deck_driver::MacroDeck;
+
+use super::models::{AutoProfileConfig, Config, RuleCondition};
+use super::profile::{apply_profile, has_override, load_active_profile};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+enum Condition {
+    Time(NaiveTime, NaiveTime),
+    Days(Vec<Weekday>),
+    Hostname(String),
+    Process(String),
+    Value {
+        name: String,
+        equals: Option<String>,
+    },
+}
+
+impl Condition {
+    fn compile(condition: &RuleCondition) -> Result<Self, String> {
+        let condition = match condition {
+            RuleCondition::Time(range) => {
+                let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+                match range.split_once('-') {
+                    Some((start, end)) => match (parse(start), parse(end)) {
+                        (Some(start), Some(end)) => Condition::Time(start, end),
+                        _ => return Err(format!("Invalid time range: {}", range)),
+                    },
+                    None => return Err(format!("Invalid time range: {}", range)),
+                }
+            }
+            RuleCondition::Days(days) => Condition::Days(
+                days.iter()
+                    .map(|day| day.parse().map_err(|_| format!("Invalid day: {}", day)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            RuleCondition::Hostname(hostname) => Condition::Hostname(hostname.clone()),
+            RuleCondition::Process(name) => Condition::Process(name.clone()),
+            RuleCondition::Value { name, equals } => Condition::Value {
+                name: name.clone(),
+                equals: equals.clone(),
+            },
+        };
+
+        Ok(condition)
+    }
+
+    fn matches(&self, context: &mut Context) -> bool {
+        match self {
+            Condition::Time(start, end) => {
+                let now = context.now.time();
+                if start <= end {
+                    *start <= now && now < *end
+                } else {
+                    // Wraps past midnight
+                    *start <= now || now < *end
+                }
+            }
+            Condition::Days(days) => days.contains(&context.now.weekday()),
+            Condition::Hostname(hostname) => context
+                .hostname
+                .as_ref()
+                .is_some_and(|current| current.eq_ignore_ascii_case(hostname)),
+            Condition::Process(name) => context.processes().contains(&name.to_lowercase()),
+            Condition::Value { name, equals } => match (context.values.get(name), equals) {
+                (Some(value), Some(equals)) => value == equals,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+        }
+    }
+}
+
+struct Rule {
+    profile: String,
+    when: Vec<Condition>,
+}
+
+/// Compiled `auto_profile` config
+pub struct Rules {
+    rules: Vec<Rule>,
+    interval: Duration,
+    debounce: Duration,
+}
+
+impl Rules {
+    pub fn compile(config: &AutoProfileConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    profile: rule.profile.clone(),
+                    when: rule
+                        .when
+                        .iter()
+                        .map(Condition::compile)
+                        .collect::<Result<_, String>>()
+                        .map_err(|e| format!("Rule for {}: {}", rule.profile, e))?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Rules {
+            rules,
+            interval: Duration::from_millis(config.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS)),
+            debounce: Duration::from_millis(config.debounce_ms.unwrap_or_default()),
+        })
+    }
+
+    /// Rules of `config`, if it has valid ones
+    pub fn load(config: &Config) -> Option<Self> {
+        let auto_profile = config.auto_profile.as_ref()?;
+        match Rules::compile(auto_profile) {
+            Ok(rules) => Some(rules),
+            Err(e) => {
+                error!("Invalid auto_profile rules: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Profile of the first rule whose conditions all hold
+    fn evaluate(&self, context: &mut Context) -> Option<String> {
+        self.rules
+            .iter()
+            .find(|rule| rule.when.iter().all(|condition| condition.matches(context)))
+            .map(|rule| rule.profile.clone())
+    }
+}
+
+/// What the conditions are checked against, gathered once per evaluation
+struct Context<'a> {
+    now: chrono::DateTime<Local>,
+    hostname: &'a Option<String>,
+    values: &'a HashMap<String, String>,
+    /// Listed on first use, most rules do not need it
+    processes: Option<HashSet<String>>,
+}
+
+impl Context<'_> {
+    fn processes(&mut self) -> &HashSet<String> {
+        self.processes.get_or_insert_with(running_processes)
+    }
+}
+
+fn hostname() -> Option<String> {
+    let output = Command::new("hostname").output().ok()?;
+    let hostname = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    (!hostname.is_empty()).then_some(hostname)
+}
+
+/// Lowercase names of the running processes, with and without extension
+fn running_processes() -> HashSet<String> {
+    let mut processes = HashSet::new();
+    for name in list_processes() {
+        let name = Path::new(name.trim())
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if let Some((stem, _)) = name.rsplit_once('.') {
+            processes.insert(stem.to_string());
+        }
+        processes.insert(name);
+    }
+
+    processes
+}
+
+#[cfg(not(any(unix, windows)))]
+fn list_processes() -> Vec<String> {
+    vec![]
+}
+
+#[cfg(windows)]
+fn list_processes() -> Vec<String> {
+    let output = match Command::new("tasklist")
+        .args(["/fo", "csv", "/nh"])
+        .output()
+    {
+        Ok(output) => output,
+        Err(_) => return vec![],
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split(',').next())
+        .map(|name| name.trim_matches('"').to_string())
+        .collect()
+}
+
+#[cfg(unix)]
+fn list_processes() -> Vec<String> {
+    let output = match Command::new("ps").args(["-A", "-o", "comm="]).output() {
+        Ok(output) => output,
+        Err(_) => return vec![],
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Holds a new winner back until it has been winning for the debounce
+#[derive(Default)]
+struct Debounce {
+    /// The profile that has been winning since the instant
+    pending: Option<(String, Instant)>,
+}
+
+impl Debounce {
+    /// Whether `winner`, still winning at `now`, has been for `debounce`.
+    fn ready(&mut self, winner: &str, now: Instant, debounce: Duration) -> bool {
+        let since = match &self.pending {
+            Some((profile, since)) if profile == winner => *since,
+            _ => {
+                debug!("Rules picked profile {}", winner);
+                self.pending = Some((winner.to_string(), now));
+                now
+            }
+        };
+
+        now.duration_since(since) >= debounce
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+/// Switches the profile of the device when the winning rule changes.
+pub struct RulesEngine {
+    rules: Mutex<Option<Rules>>,
+    values: Mutex<HashMap<String, String>>,
+}
+
+impl RulesEngine {
+    /// Start evaluating `rules` in the background.
+    pub fn start(deck: Arc<MacroDeck>, state_dir: PathBuf, rules: Option<Rules>) -> Arc<Self> {
+        let engine = Arc::new(RulesEngine {
+            rules: Mutex::new(rules),
+            values: Mutex::new(HashMap::new()),
+        });
+
+        let engine_clone = engine.clone();
+        thread::spawn(move || engine_clone.run(&deck, &state_dir));
+
+        engine
+    }
+
+    pub fn set_rules(&self, rules: Option<Rules>) {
+        *self.rules.lock().unwrap() = rules;
+    }
+
+    /// Set a value for the `value` conditions, or remove it with `None`.
+    pub fn set_value(&self, name: &str, value: Option<String>) {
+        let mut values = self.values.lock().unwrap();
+        match value {
+            Some(value) => values.insert(name.to_string(), value),
+            None => values.remove(name),
+        };
+    }
+
+    /// The winning profile with the interval and the debounce of the rules,
+    /// `None` without rules
+    fn evaluate(&self, hostname: &Option<String>) -> Option<(Option<String>, Duration, Duration)> {
+        let rules = self.rules.lock().unwrap();
+        let rules = rules.as_ref()?;
+
+        let values = self.values.lock().unwrap();
+        let mut context = Context {
+            now: Local::now(),
+            hostname,
+            values: &values,
+            processes: None,
+        };
+
+        Some((rules.evaluate(&mut context), rules.interval, rules.debounce))
+    }
+
+    fn run(&self, deck: &MacroDeck, state_dir: &Path) {
+        let hostname = hostname();
+        let mut pending = Debounce::default();
+        let mut failed: Option<String> = None;
+
+        loop {
+            let (winner, interval, debounce) = match self.evaluate(&hostname) {
+                Some(evaluated) => evaluated,
+                None => {
+                    thread::sleep(Duration::from_millis(DEFAULT_INTERVAL_MS));
+                    continue;
+                }
+            };
+
+            let winner = match winner {
+                Some(winner) if !has_override(state_dir) => winner,
+                _ => {
+                    pending.reset();
+                    thread::sleep(interval);
+                    continue;
+                }
+            };
+
+            if load_active_profile(state_dir).as_ref() == Some(&winner) {
+                pending.reset();
+                failed = None;
+                thread::sleep(interval);
+                continue;
+            }
+
+            if pending.ready(&winner, Instant::now(), debounce) {
+                match apply_profile(deck, state_dir, &winner) {
+                    Ok(()) => {
+                        info!("Rules switched to profile {}", winner);
+                        pending.reset();
+                        failed = None;
+                    }
+                    // Only report the failure once
+                    Err(e) if failed.as_ref() != Some(&winner) => {
+                        warn!("Rules failed to switch profile: {}", e);
+                        failed = Some(winner);
+                    }
+                    Err(_) => {}
+                }
+            }
+
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn rules(config: serde_json::Value) -> Result<Rules, String> {
+        Rules::compile(&serde_json::from_value(config).unwrap())
+    }
+
+    /// Evaluate at `time` on Monday 2026-10-19
+    fn evaluate(rules: &Rules, time: (u32, u32), values: &[(&str, &str)]) -> Option<String> {
+        let values = values
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut context = Context {
+            now: Local
+                .with_ymd_and_hms(2026, 10, 19, time.0, time.1, 0)
+                .unwrap(),
+            hostname: &Some("desk".to_string()),
+            values: &values,
+            processes: Some(HashSet::new()),
+        };
+
+        rules.evaluate(&mut context)
+    }
+
+    #[test]
+    fn compile_reports_the_rule_of_an_invalid_condition() {
+        let error = rules(json!({
+            "rules": [
+                { "profile": "work", "when": [{ "time": "09:00-17:00" }] },
+                { "profile": "late", "when": [{ "time": "9-5" }] },
+            ]
+        }))
+        .err()
+        .unwrap();
+        assert_eq!(error, "Rule for late: Invalid time range: 9-5");
+
+        let error = rules(json!({
+            "rules": [{ "profile": "weekend", "when": [{ "days": ["sat", "someday"] }] }]
+        }))
+        .err()
+        .unwrap();
+        assert_eq!(error, "Rule for weekend: Invalid day: someday");
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(json!({
+            "rules": [
+                { "profile": "stream", "when": [{ "value": { "name": "scene", "equals": "live" } }] },
+                { "profile": "work", "when": [{ "time": "09:00-17:00" }, { "days": ["mon", "tue"] }] },
+                { "profile": "night", "when": [{ "time": "22:00-06:00" }] },
+                { "profile": "default" },
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(evaluate(&rules, (10, 0), &[]).as_deref(), Some("work"));
+        assert_eq!(
+            evaluate(&rules, (10, 0), &[("scene", "live")]).as_deref(),
+            Some("stream")
+        );
+        assert_eq!(
+            evaluate(&rules, (10, 0), &[("scene", "intro")]).as_deref(),
+            Some("work")
+        );
+        assert_eq!(evaluate(&rules, (23, 30), &[]).as_deref(), Some("night"));
+        assert_eq!(evaluate(&rules, (5, 59), &[]).as_deref(), Some("night"));
+        assert_eq!(evaluate(&rules, (18, 0), &[]).as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn no_rule_matching_keeps_the_profile() {
+        let rules = rules(json!({
+            "rules": [
+                { "profile": "office", "when": [{ "hostname": "office-pc" }] },
+                { "profile": "weekend", "when": [{ "days": ["sat", "sun"] }] },
+                { "profile": "game", "when": [{ "process": "game.exe" }] },
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(evaluate(&rules, (12, 0), &[]), None);
+    }
+
+    #[test]
+    fn debounce_waits_for_the_same_winner() {
+        let debounce = Duration::from_millis(500);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pending = Debounce::default();
+
+        assert!(!pending.ready("work", at(0), debounce));
+        assert!(!pending.ready("work", at(400), debounce));
+        // A new winner starts over
+        assert!(!pending.ready("night", at(450), debounce));
+        assert!(!pending.ready("night", at(900), debounce));
+        assert!(pending.ready("night", at(950), debounce));
+
+        pending.reset();
+        assert!(!pending.ready("night", at(1000), debounce));
+        assert!(Debounce::default().ready("work", at(0), Duration::ZERO));
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::Cursor;
+
+use base64::{engine::general_purpose, Engine as _};
+use image::{imageops, DynamicImage, ImageFormat, Rgb, RgbImage};
+use log::warn;
+use macro_deck_driver::MacroDeck;
+use serde_json::json;
+
+use super::client::request;
+use super::flash::{aio_path, button_path};
+use super::profile::active_profile_folder;
+use super::state::device_state_dir;
+
+/// Read the images of `folder` and the status bar back and lay them out like
+/// the screen of the device, with the gaps left black.
+pub fn capture(deck: &MacroDeck, folder: &str) -> Result<RgbImage, String> {
+    let info = deck
+        .get_info()
+        .map_err(|e| format!("Failed to get device info: {}", e))?;
+    let existing = deck
+        .get_tree()
+        .map_err(|e| format!("Failed to list directory: {}", e))?;
+    let exists = |path: &str| existing.contains(path);
+
+    let mut screen = RgbImage::from_pixel(info.width, info.height, Rgb([0, 0, 0]));
+
+    let aio = aio_path(folder);
+    if exists(&aio) {
+        let image = deck
+            .fetch_icon(&aio)
+            .map_err(|e| format!("Failed to read {}: {}", aio, e))?;
+        imageops::overlay(&mut screen, &image.to_rgb8(), 0, 0);
+    } else {
+        // Stored in the buttons layout
+        let mut found = false;
+        for idx in 0..(info.buttons_per_row * info.num_of_rows) as usize {
+            let path = button_path(folder, idx);
+            if !exists(&path) {
+                continue;
+            }
+
+            let image = deck
+                .fetch_icon(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let col = idx as u32 % info.buttons_per_row;
+            let row = idx as u32 / info.buttons_per_row;
+            let x = col * (info.button_size + info.gap_size);
+            let y = row * (info.button_size + info.gap_size);
+            imageops::overlay(&mut screen, &image.to_rgb8(), x as i64, y as i64);
+            found = true;
+        }
+
+        if !found {
+            return Err(format!("No images found in {}", folder));
+        }
+    }
+
+    // The firmware cannot read the status bar back, use the last one sent
+    match deck.get_status() {
+        Ok(status) => {
+            let y = info.height - info.status_bar_height;
+            imageops::overlay(&mut screen, &status.to_rgb8(), 0, y as i64);
+        }
+        Err(e) => warn!("Status bar left empty: {}", e),
+    }
+
+    Ok(screen)
+}
+
+pub fn encode_png(image: &RgbImage) -> Result<Vec<u8>, String> {
+    let mut png = Vec::new();
+    DynamicImage::ImageRgb8(image.clone())
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+
+    Ok(png)
+}
+
+/// Screenshot of `folder`, the active profile by default, taken by the driver.
+fn request_screenshot(tcp_port: &str, folder: Option<&str>) -> Result<Vec<u8>, String> {
+    let value = request(tcp_port, "screenshot", Some(json!({ "folder": folder })))?;
+    let encoded = value
+        .get("image")
+        .and_then(|image| image.as_str())
+        .ok_or("Invalid response".to_string())?;
+    general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| "Failed to decode image".to_string())
+}
+
+pub fn screenshot(
+    tcp_port: Option<String>,
+    port: Option<String>,
+    folder: Option<String>,
+    out: Option<String>,
+) {
+    let out = out.unwrap_or("screenshot.png".to_string());
+
+    let png = match port {
+        Some(port) => match MacroDeck::new(&port) {
+            Ok(deck) => {
+                let folder =
+                    folder.unwrap_or_else(|| active_profile_folder(&device_state_dir(&port)));
+                capture(&deck, &folder).and_then(|image| encode_png(&image))
+            }
+            Err(e) => Err(format!("Failed to open {}: {}", port, e)),
+        },
+        None => request_screenshot(&tcp_port.unwrap_or("8964".to_string()), folder.as_deref()),
+    };
+
+    let result = png.and_then(|png| {
+        std::fs::write(&out, png).map_err(|e| format!("Failed to write {}: {}", out, e))
+    });
+    match result {
+        Ok(()) => println!("Wrote screenshot to {}", out),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{path::Path, thread, time::Duration};
+
+use log::{info, warn};
+use macro_deck_driver::MacroDeck;
+
+use super::actions::execute;
+use super::dispatch::Job;
+use super::models::{ButtonAction, SequenceStep, StepKind};
+
+enum StepResult {
+    /// Ruled out by `if_exit_code`
+    Skipped,
+    /// Succeeded, or failed with `continue_on_error`. Holds the exit code of
+    /// a command.
+    Done(Option<i32>),
+    Failed(String),
+}
+
+/// What the steps of a sequence share
+#[derive(Clone, Copy)]
+struct Runner<'a> {
+    deck: &'a MacroDeck,
+    state_dir: &'a Path,
+    key: &'a str,
+    job: &'a Job,
+}
+
+impl Runner<'_> {
+    /// Run one step, with the exit code of a command. `name` numbers the step
+    /// in the logs, such as `2`, or `2.1` inside a parallel group.
+    fn run_step(
+        self,
+        name: &str,
+        step: &SequenceStep,
+        last_exit_code: Option<i32>,
+    ) -> (Result<String, String>, Option<i32>) {
+        match &step.kind {
+            StepKind::Delay { delay_ms } => (
+                self.job
+                    .sleep(Duration::from_millis(*delay_ms))
+                    .map(|()| format!("Waited {} ms", delay_ms)),
+                None,
+            ),
+            StepKind::Action(action) => {
+                execute(self.deck, self.state_dir, self.key, action, self.job)
+            }
+            StepKind::Parallel { parallel } => {
+                let results: Vec<StepResult> = thread::scope(|scope| {
+                    let handles: Vec<_> = parallel
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, child)| {
+                            let name = format!("{}.{}", name, idx + 1);
+                            scope
+                                .spawn(move || self.run_logged(&name, child, last_exit_code, false))
+                        })
+                        .collect();
+
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle
+                                .join()
+                                .unwrap_or(StepResult::Failed("The step panicked".to_string()))
+                        })
+                        .collect()
+                });
+
+                let failed = results
+                    .iter()
+                    .filter(|result| matches!(result, StepResult::Failed(_)))
+                    .count();
+                let result = if failed == 0 {
+                    Ok(format!("{} steps done", parallel.len()))
+                } else {
+                    Err(format!("{} of {} steps failed", failed, parallel.len()))
+                };
+                (result, None)
+            }
+        }
+    }
+
+    /// Run a step unless `if_exit_code` rules it out and log its result. A
+    /// command exiting with an error does not fail the step when `branched`,
+    /// a later step checks its exit code instead.
+    fn run_logged(
+        self,
+        name: &str,
+        step: &SequenceStep,
+        last_exit_code: Option<i32>,
+        branched: bool,
+    ) -> StepResult {
+        let key = self.key;
+        if !step.runs_after(last_exit_code) {
+            info!(
+                "[{}] Step {} skipped, the last exit code was {}",
+                key,
+                name,
+                last_exit_code.map_or("none".to_string(), |code| code.to_string())
+            );
+            return StepResult::Skipped;
+        }
+
+        let (result, exit_code) = self.run_step(name, step, last_exit_code);
+        match result {
+            Ok(done) => {
+                info!("[{}] Step {}: {}", key, name, done);
+                StepResult::Done(exit_code)
+            }
+            Err(e) if branched && exit_code.is_some() => {
+                info!("[{}] Step {}: {}, checked by a later step", key, name, e);
+                StepResult::Done(exit_code)
+            }
+            Err(e) if step.continue_on_error => {
+                warn!("[{}] Step {} failed, continuing: {}", key, name, e);
+                StepResult::Done(exit_code)
+            }
+            Err(e) => {
+                warn!("[{}] Step {} failed: {}", key, name, e);
+                StepResult::Failed(e)
+            }
+        }
+    }
+}
+
+/// Whether one of `later` steps checks the exit code of the command before
+/// them with `if_exit_code`, before another command replaces it.
+fn branches_on_exit_code(later: &[SequenceStep]) -> bool {
+    for step in later {
+        if step.if_exit_code.is_some() {
+            return true;
+        }
+        if matches!(
+            step.kind,
+            StepKind::Action(ButtonAction::Exec { .. } | ButtonAction::Shell { .. })
+        ) {
+            return false;
+        }
+    }
+
+    false
+}
+
+/// Run the steps of a sequence in order. Stops at the first failed step
+/// without `continue_on_error`, or when the job has to stop. A command whose
+/// exit code a later step checks does not count as failed.
+pub fn run_sequence(
+    deck: &MacroDeck,
+    state_dir: &Path,
+    key: &str,
+    steps: &[SequenceStep],
+    job: &Job,
+) -> Result<String, String> {
+    let runner = Runner {
+        deck,
+        state_dir,
+        key,
+        job,
+    };
+    let mut last_exit_code = None;
+    let mut done = 0;
+    for (idx, step) in steps.iter().enumerate() {
+        let name = (idx + 1).to_string();
+        job.check()
+            .map_err(|e| format!("Stopped at step {}: {}", name, e))?;
+        let branched = branches_on_exit_code(&steps[idx + 1..]);
+        match runner.run_logged(&name, step, last_exit_code, branched) {
+            StepResult::Skipped => {}
+            StepResult::Done(exit_code) => {
+                done += 1;
+                if exit_code.is_some() {
+                    last_exit_code = exit_code;
+                }
+            }
+            StepResult::Failed(e) => return Err(format!("Stopped at step {}: {}", name, e)),
+        }
+    }
+
+    Ok(format!("Ran {} of {} steps", done, steps.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn steps(value: serde_json::Value) -> Vec<SequenceStep> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn a_later_if_exit_code_checks_the_last_command() {
+        let later = steps(json!([{ "delay_ms": 500 }, { "delay_ms": 1, "if_exit_code": 1 }]));
+        assert!(branches_on_exit_code(&later));
+
+        // The next command replaces the exit code before it is checked
+        let later = steps(json!([
+            { "action": "shell", "command": "true" },
+            { "delay_ms": 1, "if_exit_code": 1 }
+        ]));
+        assert!(!branches_on_exit_code(&later));
+
+        let later =
+            steps(json!([{ "delay_ms": 500 }, { "action": "switchProfile", "profile": "a" }]));
+        assert!(!branches_on_exit_code(&later));
+        assert!(!branches_on_exit_code(&[]));
+    }
+}
@@ -2,22 +2,39 @@ use base64::{engine::general_purpose, Engine as _};
 use log::{debug, error, info, warn};
 use macro_deck_driver::MacroDeck;
 use regex::Regex;
-use serde_json::json;
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::{
+    collections::BTreeMap,
     fs,
     io::{BufRead, BufReader, Write as _},
     net::{TcpListener, TcpStream},
+    path::Path,
     process::Stdio,
     sync::{Arc, Mutex},
     thread,
+    time::Duration,
 };
 
 use crate::cli::{
-    flash::flash_device,
-    models::{Config, Message},
+    actions::run_action,
+    dispatch::{Dispatcher, Handler, DEFAULT_WORKERS},
+    flash::{flash_device, forget_manifest, update_button_icon, FlashResult},
+    fs::handle as handle_fs,
+    models::{
+        ButtonAction, ButtonConfig, Config, FlashRequest, FsRequest, Message, SetIconRequest,
+        SetValueRequest,
+    },
+    profile::{
+        active_profile_folder, clear_override, load_active_profile, restore_profile, switch_profile,
+    },
+    rules::{Rules, RulesEngine},
+    screenshot::{capture, encode_png},
+    state::device_state_dir,
+    toggle::Toggle,
 };
 
-const MAX_TRIES: u32 = 5;
+pub const MAX_TRIES: u32 = 5;
 
 #[cfg(not(any(unix, windows)))]
 fn auto_detect_port() -> Option<String> {
@@ -49,7 +66,7 @@ fn auto_detect_port() -> Option<String> {
     }
 }
 
-fn read_and_parse_config(config_path: &str) -> Option<Config> {
+pub fn read_and_parse_config(config_path: &str) -> Option<Config> {
     let config_content = match fs::read_to_string(config_path) {
         Ok(content) => content,
         Err(_) => {
@@ -58,10 +75,22 @@ fn read_and_parse_config(config_path: &str) -> Option<Config> {
         }
     };
 
-    match serde_json::from_str(&config_content) {
-        Ok(config) => Some(config),
-        Err(_) => {
-            warn!("Failed to parse config.json");
+    match serde_json::from_str::<Config>(&config_content) {
+        Ok(mut config) => {
+            config.base_dir = Path::new(config_path)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            config.expand_profiles();
+            config.expand_toggles();
+            if let Err(e) = config.validate() {
+                warn!("Invalid config.json: {}", e);
+                return None;
+            }
+            Some(config)
+        }
+        Err(e) => {
+            warn!("Failed to parse config.json: {}", e);
             None
         }
     }
@@ -106,13 +135,18 @@ pub fn start(port: Option<String>, config_path: Option<String>, tcp_port: Option
     };
 
     let deck = Arc::new(MacroDeck::new(&port).expect("Failed to create MacroDeck instance"));
+    let state_dir = device_state_dir(&port);
 
     info!("Starting status handler...");
-    if let Some(status) = config.status.clone() {
-        if let Some(command) = status.command {
+    match config
+        .status
+        .as_ref()
+        .and_then(|status| status.action.clone())
+    {
+        Some(ButtonAction::Exec { command, args }) => {
             thread::spawn(move || loop {
                 let _ = std::process::Command::new(command.clone())
-                    .args(status.args.clone().unwrap_or_default())
+                    .args(args.iter().flatten())
                     .stderr(Stdio::null())
                     .stdout(Stdio::null())
                     .spawn()
@@ -122,6 +156,8 @@ pub fn start(port: Option<String>, config_path: Option<String>, tcp_port: Option
                 error!("Status handler crashed, restarting...");
             });
         }
+        Some(_) => warn!("The status handler has to be a command"),
+        None => {}
     }
 
     info!("Registering status handler...");
@@ -148,43 +184,61 @@ pub fn start(port: Option<String>, config_path: Option<String>, tcp_port: Option
         });
     }
 
-    if let Some(buttons) = config.buttons.clone() {
+    // Toggles redraw their icon, which changes the config kept for updates
+    let rules = Rules::load(&config);
+    let buttons = config.buttons.clone();
+    let handlers = config.handlers.clone().unwrap_or_default();
+    let config = Arc::new(Mutex::new(config));
+
+    let dispatcher = Dispatcher::start(handlers.workers.unwrap_or(DEFAULT_WORKERS));
+
+    let mut toggles = vec![];
+    if let Some(buttons) = buttons {
         info!("Registering button handlers...");
         for (key, button) in buttons.iter() {
-            let command = button.command.clone();
-            if command.is_none() {
+            // The deck owns its handlers, a strong reference would never be dropped
+            let weak_deck = Arc::downgrade(&deck);
+            let state_dir = state_dir.clone();
+            let handler: Handler = if let Some(toggle) = button.toggle.clone() {
+                let toggle = Arc::new(Toggle::new(key, toggle));
+                toggles.push(toggle.clone());
+
+                let config = config.clone();
+                Arc::new(move |job| {
+                    let deck = weak_deck.upgrade().ok_or("The driver stopped")?;
+                    toggle.press(&deck, &config, &state_dir, job)
+                })
+            } else if let Some(action) = button.action.clone() {
+                let key = key.clone();
+                Arc::new(move |job| {
+                    let deck = weak_deck.upgrade().ok_or("The driver stopped")?;
+                    run_action(&deck, &state_dir, &key, &action, job)
+                })
+            } else {
                 continue;
-            }
+            };
 
-            let key = key.clone();
-            let command = command.unwrap();
-            let args = button.args.clone().unwrap_or_default();
-
-            deck.register_handler(&key.clone(), move || {
-                let output = std::process::Command::new(command.clone())
-                    .args(args.clone())
-                    .output();
-
-                if output.is_err() {
-                    warn!("[{}] Failed to execute command: {}", key, command);
-                } else {
-                    let output = output.unwrap();
-                    debug!(
-                        "[{}] Command output: {}",
-                        key,
-                        if output.stdout.is_empty() {
-                            "None".to_string()
-                        } else {
-                            String::from_utf8_lossy(&output.stdout).to_string()
-                        }
-                    );
-                }
-            });
+            register_handler(
+                &deck,
+                &dispatcher,
+                key,
+                button,
+                handlers.timeout_ms,
+                handler,
+            );
         }
     }
 
     info!("Start listening to the serial port: {}", port);
     deck.start();
+    restore_profile(&deck, &state_dir);
+
+    for toggle in toggles {
+        toggle.watch(deck.clone(), config.clone(), state_dir.clone());
+    }
+
+    info!("Starting profile rules...");
+    let rules_engine = RulesEngine::start(deck.clone(), state_dir.clone(), rules);
 
     // TCP server
     let tcp_port = tcp_port.unwrap_or("8964".to_string());
@@ -236,28 +290,204 @@ pub fn start(port: Option<String>, config_path: Option<String>, tcp_port: Option
                     stop_flag = true;
                     break;
                 }
+                "getInfo" => {
+                    debug!("Sending device info...");
+
+                    match deck.get_info() {
+                        Ok(info) => {
+                            let mesg = Message {
+                                type_: "getInfo".to_string(),
+                                value: Some(json!([
+                                    info.width,
+                                    info.height,
+                                    info.buttons_per_row,
+                                    info.num_of_rows,
+                                    info.gap_size
+                                ])),
+                            };
+
+                            if writeln!(stream, "{}", serde_json::to_string(&mesg).unwrap())
+                                .is_err()
+                            {
+                                warn!("Failed to write to stream");
+                            }
+                        }
+                        Err(e) => warn!("Failed to get info: {}", e),
+                    }
+
+                    break;
+                }
+                "getProfile" => {
+                    debug!("Sending the active profile...");
+
+                    send_message(&stream, "getProfile", load_active_profile(&state_dir));
+                    break;
+                }
                 "flash" => {
                     debug!("Flashing the device...");
 
-                    let config = if let Some(config_path) = msg.value {
-                        let config_path = match config_path.as_str() {
-                            Some(path) => path,
-                            None => {
-                                warn!("Invalid config path");
-                                continue;
-                            }
-                        };
+                    let current = config.lock().unwrap().clone();
+                    if let Some(flashed) =
+                        handle_flash(&deck, &current, &port, &state_dir, msg.value, &stream)
+                    {
+                        rules_engine.set_rules(Rules::load(&flashed));
+                        *config.lock().unwrap() = flashed;
+                        // The folder of the active profile may have been recreated
+                        restore_profile(&deck, &state_dir);
+                    }
+                    break;
+                }
+                "switchProfile" => {
+                    debug!("Switching profile...");
+
+                    let result = msg
+                        .value
+                        .as_ref()
+                        .and_then(|value| value.as_str())
+                        .ok_or("No profile provided".to_string())
+                        .and_then(|name| switch_profile(&deck, &state_dir, name));
+
+                    if let Err(e) = &result {
+                        warn!("Failed to switch profile: {}", e);
+                    }
+                    send_message(
+                        &stream,
+                        "switchProfile",
+                        json!({
+                            "success": result.is_ok(),
+                            "error": result.err(),
+                        }),
+                    );
+                    break;
+                }
+                "setValue" => {
+                    debug!("Setting rule value...");
+
+                    let result = msg
+                        .value
+                        .ok_or("No value provided".to_string())
+                        .and_then(|value| {
+                            serde_json::from_value::<SetValueRequest>(value)
+                                .map_err(|e| format!("Invalid value: {}", e))
+                        })
+                        .map(|request| {
+                            let value = match request.value {
+                                None | Some(Value::Null) => None,
+                                Some(Value::String(value)) => Some(value),
+                                Some(value) => Some(value.to_string()),
+                            };
+                            rules_engine.set_value(&request.name, value);
+                        });
+
+                    if let Err(e) = &result {
+                        warn!("Failed to set value: {}", e);
+                    }
+                    send_message(
+                        &stream,
+                        "setValue",
+                        json!({
+                            "success": result.is_ok(),
+                            "error": result.err(),
+                        }),
+                    );
+                    break;
+                }
+                "autoProfile" => {
+                    debug!("Clearing the profile override...");
 
-                        match read_and_parse_config(config_path) {
-                            Some(config) => config,
-                            None => continue,
+                    let result = clear_override(&state_dir);
+                    if let Err(e) = &result {
+                        warn!("{}", e);
+                    }
+                    send_message(
+                        &stream,
+                        "autoProfile",
+                        json!({
+                            "success": result.is_ok(),
+                            "error": result.err(),
+                        }),
+                    );
+                    break;
+                }
+                "screenshot" => {
+                    debug!("Taking a screenshot...");
+
+                    let folder = msg
+                        .value
+                        .as_ref()
+                        .and_then(|value| value.get("folder"))
+                        .and_then(|folder| folder.as_str())
+                        .map(str::to_string)
+                        .unwrap_or_else(|| active_profile_folder(&state_dir));
+
+                    let value = match capture(&deck, &folder).and_then(|image| encode_png(&image)) {
+                        Ok(png) => json!({ "image": general_purpose::STANDARD.encode(png) }),
+                        Err(e) => {
+                            warn!("Failed to take a screenshot: {}", e);
+                            json!({ "error": e })
                         }
-                    } else {
-                        config.clone()
                     };
-
-                    flash_device(&deck, &config);
-
+                    send_message(&stream, "screenshot", value);
+                    break;
+                }
+                "fs" => {
+                    debug!("Running filesystem command...");
+
+                    let result = msg
+                        .value
+                        .ok_or("No command provided".to_string())
+                        .and_then(|value| {
+                            serde_json::from_value::<FsRequest>(value)
+                                .map_err(|e| format!("Invalid filesystem command: {}", e))
+                        })
+                        .and_then(|request| {
+                            if request.modifies() {
+                                forget_manifest(&state_dir);
+                            }
+                            handle_fs(&deck, request)
+                        });
+
+                    let value = match result {
+                        Ok(result) => json!({ "result": result }),
+                        Err(e) => {
+                            warn!("Filesystem command failed: {}", e);
+                            json!({ "error": e })
+                        }
+                    };
+                    send_message(&stream, "fs", value);
+                    break;
+                }
+                "setIcon" => {
+                    debug!("Setting icon...");
+
+                    let result = msg
+                        .value
+                        .ok_or("No icon provided".to_string())
+                        .and_then(|value| {
+                            serde_json::from_value::<SetIconRequest>(value)
+                                .map_err(|e| format!("Invalid icon request: {}", e))
+                        })
+                        .and_then(|request| {
+                            update_button_icon(
+                                &deck,
+                                &mut config.lock().unwrap(),
+                                &state_dir,
+                                &request.button,
+                                Some(request.icon),
+                            )
+                        });
+
+                    if let Err(e) = &result {
+                        warn!("Failed to set icon: {}", e);
+                    }
+                    send_message(
+                        &stream,
+                        "setIcon",
+                        json!({
+                            "success": result.is_ok(),
+                            "error": result.err(),
+                        }),
+                    );
                     break;
                 }
                 _ => {
@@ -317,6 +547,107 @@ pub fn start(port: Option<String>, config_path: Option<String>, tcp_port: Option
     }
 }
 
+/// Register the handler of the button `key`. Presses are handed to the
+/// dispatcher, the serial reader thread runs the handlers of the deck and has
+/// to stay free to read the replies of the device.
+fn register_handler(
+    deck: &MacroDeck,
+    dispatcher: &Arc<Dispatcher>,
+    key: &str,
+    button: &ButtonConfig,
+    default_timeout_ms: Option<u64>,
+    handler: Handler,
+) {
+    let dispatcher = dispatcher.clone();
+    let key = key.to_string();
+    let policy = button.concurrency.unwrap_or_default();
+    let timeout = button
+        .timeout_ms
+        .or(default_timeout_ms)
+        .map(Duration::from_millis);
+
+    deck.register_handler(&key.clone(), move || {
+        dispatcher.dispatch(&key, policy, timeout, handler.clone())
+    });
+}
+
+fn send_message(mut stream: &TcpStream, type_: &str, value: impl Serialize) {
+    let mesg = Message {
+        type_: type_.to_string(),
+        value: Some(json!(value)),
+    };
+
+    // The client may have gone away, the flash goes on regardless
+    if writeln!(stream, "{}", serde_json::to_string(&mesg).unwrap()).is_err() {
+        debug!("Failed to write to stream");
+    }
+}
+
+/// Flash the device and stream the progress and the result back to the client.
+/// Returns the flashed config on success.
+fn handle_flash(
+    deck: &MacroDeck,
+    config: &Config,
+    port: &str,
+    state_dir: &Path,
+    value: Option<Value>,
+    stream: &TcpStream,
+) -> Option<Config> {
+    let request = match value {
+        None => Ok(FlashRequest::default()),
+        // Older clients only send the config path
+        Some(Value::String(config_path)) => Ok(FlashRequest {
+            config_path: Some(config_path),
+            ..Default::default()
+        }),
+        Some(value) => {
+            serde_json::from_value(value).map_err(|e| format!("Invalid flash request: {}", e))
+        }
+    };
+
+    let mut folders = BTreeMap::new();
+    let result = request.and_then(|request| {
+        let config = match request.config_path {
+            Some(config_path) => read_and_parse_config(&config_path)
+                .ok_or(format!("Failed to load config: {}", config_path))?,
+            None => config.clone(),
+        };
+
+        let mut options = request.options;
+        options.layout = options.layout.or(config.device_layout(port));
+
+        flash_device(deck, &config, state_dir, options, &mut |progress| {
+            // Backing up a folder does not change how its flash went
+            let status = folders
+                .entry(progress.dir.clone())
+                .or_insert(progress.status);
+            *status = (*status).max(progress.status);
+            send_message(stream, "flashProgress", progress);
+        })
+        .map(|()| config)
+    });
+
+    if let Err(e) = &result {
+        error!("Flash failed: {}", e);
+    }
+
+    let (flashed, error) = match result {
+        Ok(config) => (Some(config), None),
+        Err(e) => (None, Some(e)),
+    };
+    send_message(
+        stream,
+        "flash",
+        FlashResult {
+            success: flashed.is_some(),
+            error,
+            folders,
+        },
+    );
+
+    flashed
+}
+
 fn status_tcp_stream_read_handler(stream: TcpStream, deck: Arc<MacroDeck>) {
     thread::spawn(move || loop {
         let reader = BufReader::new(&stream);
@@ -0,0 +1,23 @@
+use std::{env, path::PathBuf};
+
+/// Directory for data that should survive restarts, such as flash backups.
+pub fn state_dir() -> PathBuf {
+    let base = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(env::temp_dir);
+
+    base.join("macro-deck-driver")
+}
+
+/// State directory of the device connected to the given serial port.
+pub fn device_state_dir(port: &str) -> PathBuf {
+    let name: String = port
+        .trim_start_matches("/dev/")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    state_dir().join(name)
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use macro_deck_driver::MacroDeck;
+
+use super::actions::{run_action, shell};
+use super::dispatch::Job;
+use super::flash::update_button_icon;
+use super::models::{Config, ToggleConfig};
+
+/// File in the state directory holding the state of every toggle
+const TOGGLES: &str = "toggles.json";
+const DEFAULT_STATE_INTERVAL_MS: u64 = 5000;
+
+/// Toggles save their state from their own threads
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// Whether each toggle is on, by button
+pub fn load_toggle_states(state_dir: &Path) -> HashMap<String, bool> {
+    fs::read_to_string(state_dir.join(TOGGLES))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn is_on(state_dir: &Path, key: &str) -> bool {
+    load_toggle_states(state_dir)
+        .get(key)
+        .copied()
+        .unwrap_or_default()
+}
+
+fn save_toggle_state(state_dir: &Path, key: &str, on: bool) {
+    let _saving = SAVING.lock().unwrap();
+
+    let mut states = load_toggle_states(state_dir);
+    states.insert(key.to_string(), on);
+    let result = fs::create_dir_all(state_dir).and_then(|()| {
+        fs::write(
+            state_dir.join(TOGGLES),
+            serde_json::to_string_pretty(&states).unwrap(),
+        )
+    });
+    if let Err(e) = result {
+        warn!("Failed to save the state of {}: {}", key, e);
+    }
+}
+
+fn state_name(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Show the icon of the saved state on every toggle button.
+pub fn apply_toggle_states(config: &mut Config, state_dir: &Path) {
+    let states = load_toggle_states(state_dir);
+    for (key, button) in config.buttons.iter_mut().flatten() {
+        if let Some(toggle) = &button.toggle {
+            let on = states.get(key).copied().unwrap_or_default();
+            button.icon = toggle.variant(on).icon.clone();
+        }
+    }
+}
+
+/// A toggle button, shared by its handler and the thread running its
+/// `state_command`
+pub struct Toggle {
+    key: String,
+    toggle: ToggleConfig,
+    /// Held while the state changes, so presses and resyncs do not interleave
+    busy: Mutex<()>,
+}
+
+impl Toggle {
+    pub fn new(key: &str, toggle: ToggleConfig) -> Self {
+        Toggle {
+            key: key.to_string(),
+            toggle,
+            busy: Mutex::new(()),
+        }
+    }
+
+    /// Flip the state, run the action of the new state and show its icon.
+    /// The state is kept when the action fails.
+    pub fn press(
+        &self,
+        deck: &MacroDeck,
+        config: &Mutex<Config>,
+        state_dir: &Path,
+        job: &Job,
+    ) -> Result<String, String> {
+        let _busy = self.busy.lock().unwrap();
+
+        let on = !is_on(state_dir, &self.key);
+        let done = match &self.toggle.variant(on).action {
+            Some(action) => run_action(deck, state_dir, &self.key, action, job)?,
+            None => "No action".to_string(),
+        };
+        self.show(deck, config, state_dir, on)?;
+
+        Ok(format!("Switched {}: {}", state_name(on), done))
+    }
+
+    /// Save the state and render its icon on the device.
+    fn show(
+        &self,
+        deck: &MacroDeck,
+        config: &Mutex<Config>,
+        state_dir: &Path,
+        on: bool,
+    ) -> Result<(), String> {
+        save_toggle_state(state_dir, &self.key, on);
+
+        update_button_icon(
+            deck,
+            &mut config.lock().unwrap(),
+            state_dir,
+            &self.key,
+            self.toggle.variant(on).icon.clone(),
+        )
+    }
+
+    /// Run `state_command` periodically in the background and follow the
+    /// state it reports, without running the actions.
+    pub fn watch(
+        self: Arc<Self>,
+        deck: Arc<MacroDeck>,
+        config: Arc<Mutex<Config>>,
+        state_dir: PathBuf,
+    ) {
+        let command = match &self.toggle.state_command {
+            Some(command) => command.clone(),
+            None => return,
+        };
+        let interval = Duration::from_millis(
+            self.toggle
+                .state_interval_ms
+                .unwrap_or(DEFAULT_STATE_INTERVAL_MS),
+        );
+
+        thread::spawn(move || loop {
+            let status = shell(&command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            match status {
+                Ok(status) => self.resync(&deck, &config, &state_dir, status.success()),
+                Err(e) => debug!("[{}] Failed to run {}: {}", self.key, command, e),
+            }
+
+            thread::sleep(interval);
+        });
+    }
+
+    fn resync(&self, deck: &MacroDeck, config: &Mutex<Config>, state_dir: &Path, on: bool) {
+        let _busy = self.busy.lock().unwrap();
+        if is_on(state_dir, &self.key) == on {
+            return;
+        }
+
+        // The state is saved even if the icon fails, so the failure is only
+        // reported once
+        match self.show(deck, config, state_dir, on) {
+            Ok(()) => info!("[{}] Resynced to {}", self.key, state_name(on)),
+            Err(e) => warn!(
+                "[{}] Failed to show the {} state: {}",
+                self.key,
+                state_name(on),
+                e
+            ),
+        }
+    }
+}
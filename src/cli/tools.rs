@@ -1,9 +1,13 @@
 use base64::{engine::general_purpose, Engine as _};
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::cli::models::Config;
 
-use super::models::ButtonConfig;
+use super::models::{Fit, IconSource, IconStyle};
+use super::render::Icon;
 
 pub fn get_all_icon_paths(from: &str, paths: &mut Vec<PathBuf>) {
     for entry in fs::read_dir(from).unwrap() {
@@ -22,8 +26,9 @@ pub fn get_all_icon_paths(from: &str, paths: &mut Vec<PathBuf>) {
     }
 }
 
-pub fn write_icons_to_config(from: String, to: Option<String>) {
-    let config_content = match fs::read_to_string(to.clone().unwrap_or("config.json".to_string())) {
+pub fn write_icons_to_config(from: String, to: Option<String>, fit: Option<Fit>, link: bool) {
+    let config_path = to.unwrap_or("config.json".to_string());
+    let config_content = match fs::read_to_string(&config_path) {
         Ok(content) => content,
         Err(_) => {
             eprintln!("Failed to read config.json");
@@ -39,6 +44,10 @@ pub fn write_icons_to_config(from: String, to: Option<String>) {
         }
     };
     let mut buttons = config.buttons.clone().unwrap_or_default();
+    let config_dir = fs::canonicalize(&config_path)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
 
     // write icons to config
     let mut icons = vec![];
@@ -55,19 +64,30 @@ pub fn write_icons_to_config(from: String, to: Option<String>) {
                 continue;
             }
         };
-        let encoded = general_purpose::STANDARD.encode(&icon_data);
+        if Icon::load(&icon_data).is_none() {
+            eprintln!("Unsupported icon file: {}", icon.display());
+            continue;
+        }
 
-        if let Some(button_config) = buttons.get_mut(&icon_name) {
-            button_config.icon = Some(encoded);
+        let source = if link {
+            // Reference the file relative to the config instead of embedding it
+            let absolute = fs::canonicalize(&icon).unwrap_or(icon.clone());
+            let relative = absolute.strip_prefix(&config_dir).unwrap_or(&absolute);
+            IconSource::Path {
+                path: relative.display().to_string(),
+            }
         } else {
-            buttons.insert(
-                icon_name,
-                ButtonConfig {
-                    command: None,
-                    args: None,
-                    icon: Some(encoded),
-                },
-            );
+            IconSource::Embedded(general_purpose::STANDARD.encode(&icon_data))
+        };
+
+        let button_config = buttons.entry(icon_name).or_default();
+        button_config.icon = Some(source);
+
+        if let Some(fit) = fit {
+            button_config.style = Some(IconStyle {
+                fit: Some(fit),
+                ..button_config.style.clone().unwrap_or_default()
+            });
         }
     }
 
@@ -82,7 +102,7 @@ pub fn write_icons_to_config(from: String, to: Option<String>) {
         }
     };
 
-    if let Err(_) = fs::write(to.unwrap_or("config.json".to_string()), config_json) {
+    if fs::write(&config_path, config_json).is_err() {
         eprintln!("Failed to write config.json");
     } else {
         println!("Icons written to config.json");
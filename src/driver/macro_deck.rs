@@ -1,8 +1,12 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io::{BufRead, BufReader, Cursor, Read},
-    path::{Path, PathBuf},
-    sync::{Arc, Condvar, Mutex},
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Cursor, Read},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
     thread,
     time::Duration,
 };
@@ -11,6 +15,7 @@ use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader};
 use serialport::SerialPort;
 
 use super::message::Message;
+use super::tree::{DeviceTree, NodeKind};
 
 const MAX_TIMEOUT: u64 = 3;
 
@@ -25,26 +30,59 @@ pub struct DeviceInfo {
     pub status_bar_height: u32,
 }
 
+impl DeviceInfo {
+    pub fn new(
+        width: u32,
+        height: u32,
+        buttons_per_row: u32,
+        num_of_rows: u32,
+        gap_size: u32,
+    ) -> Self {
+        let button_size = (width - (buttons_per_row - 1) * gap_size) / buttons_per_row;
+        let status_bar_height = height - num_of_rows * button_size - num_of_rows * gap_size;
+
+        DeviceInfo {
+            width,
+            height,
+            buttons_per_row,
+            num_of_rows,
+            gap_size,
+            button_size,
+            status_bar_height,
+        }
+    }
+}
+
+type MessageHandler = Box<dyn Fn(Message) + Send + 'static>;
+type ButtonHandler = Box<dyn Fn() + Send + 'static>;
+type StatusHandler = Box<dyn Fn(u32) + Send + 'static>;
+type Reply = Arc<(Mutex<Option<Message>>, Condvar)>;
+
 pub struct MacroDeck {
     port: Arc<Mutex<Box<dyn SerialPort>>>,
-    static_read_handler: Arc<Mutex<Option<Box<dyn Fn(Message) + Send + 'static>>>>,
-    read_handler: Arc<Mutex<Vec<Box<dyn Fn(Message) + Send + 'static>>>>,
+    static_read_handler: Arc<Mutex<Option<MessageHandler>>>,
+    /// Handlers waiting for the next reply, by request
+    read_handler: Arc<Mutex<Vec<(usize, MessageHandler)>>>,
+    next_request: AtomicUsize,
     info: Arc<Mutex<Option<DeviceInfo>>>,
     icons: Arc<Mutex<HashMap<String, DynamicImage>>>,
-    dirs: Arc<Mutex<Option<Vec<PathBuf>>>>,
+    dirs: Arc<Mutex<Option<DeviceTree>>>,
     status: Arc<Mutex<Option<DynamicImage>>>,
-    handlers: Arc<Mutex<HashMap<String, Box<dyn Fn() + Send + 'static>>>>,
-    status_handler: Arc<Mutex<Option<Box<dyn Fn(u32) + Send + 'static>>>>,
+    handlers: Arc<Mutex<HashMap<String, ButtonHandler>>>,
+    status_handler: Arc<Mutex<Option<StatusHandler>>>,
+    raw_receiver: Mutex<Receiver<Vec<u8>>>,
+    /// Held over each exchange with the device, from the request to the last
+    /// reply, so the messages of concurrent callers do not interleave
+    exchange: Mutex<()>,
 }
 
 macro_rules! send_and_check_ok {
     ($self:ident, $msg_type:expr, $data:expr, $err_msg:expr) => {{
-        $self.write(&Message::new(
+        let _exchange = $self.exchange()?;
+        let message = $self.request(&Message::new(
             $msg_type.to_string(),
             vec![$data.to_string()],
         ))?;
-
-        let message = $self.read()?;
         if message.message_type != "ok" {
             return Err($err_msg);
         }
@@ -90,21 +128,44 @@ fn find_patch(img1: &DynamicImage, img2: &DynamicImage) -> Option<(u32, u32, Dyn
     Some((min_x, min_y, patch))
 }
 
+/// Whether the port could not be opened because another process holds it.
+/// Depending on the serialport version `EBUSY` is reported as `Unknown` or
+/// `NoDevice`, which also covers missing devices, so this goes by the errno
+/// description or the failed lock instead of the kind.
+fn is_busy(e: &serialport::Error) -> bool {
+    let description = e.description.to_lowercase();
+    description.contains("resource busy") || description.contains("exclusive lock")
+}
+
+fn decode_icon(buffer: Vec<u8>) -> Result<DynamicImage, &'static str> {
+    ImageReader::new(Cursor::new(buffer))
+        .with_guessed_format()
+        .map_err(|_| "Failed to decode icon")?
+        .decode()
+        .map_err(|_| "Failed to decode icon")
+}
+
 impl MacroDeck {
     pub fn new(path: &str) -> Result<Self, &str> {
         let port = serialport::new(path, 115200)
             .timeout(std::time::Duration::from_secs(MAX_TIMEOUT))
             .open()
-            .map_err(|_| "Failed to open port")?;
+            .map_err(|e| match e.kind() {
+                _ if is_busy(&e) => "Port is in use by another process",
+                serialport::ErrorKind::Io(io::ErrorKind::NotFound) => "Port not found",
+                serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) => {
+                    "Permission denied to open port"
+                }
+                _ => "Failed to open port",
+            })?;
 
-        let read_handler: Arc<Mutex<Vec<Box<dyn Fn(Message) + Send + 'static>>>> =
-            Arc::new(Mutex::new(vec![]));
-        let static_read_handler: Arc<Mutex<Option<Box<dyn Fn(Message) + Send + 'static>>>> =
-            Arc::new(Mutex::new(None));
+        let read_handler: Arc<Mutex<Vec<(usize, MessageHandler)>>> = Arc::new(Mutex::new(vec![]));
+        let static_read_handler: Arc<Mutex<Option<MessageHandler>>> = Arc::new(Mutex::new(None));
 
         let port_clone = port.try_clone().map_err(|_| "Failed to clone port")?;
         let read_handler_clone = read_handler.clone();
         let static_read_handler_clone = static_read_handler.clone();
+        let (raw_sender, raw_receiver) = mpsc::channel();
         thread::spawn(move || {
             let mut buf_reader = BufReader::new(port_clone);
             let mut line_buffer = String::new();
@@ -128,11 +189,25 @@ impl MacroDeck {
                     handler(mesg.clone());
                 }
 
-                let mut handlers = read_handler_clone.lock().unwrap();
-                for handler in handlers.iter() {
-                    handler(mesg.clone());
+                // Button and status clicks are never replies
+                if mesg.message_type != "bc" && mesg.message_type != "sc" {
+                    let mut handlers = read_handler_clone.lock().unwrap();
+                    for (_, handler) in handlers.iter() {
+                        handler(mesg.clone());
+                    }
+                    handlers.clear();
+                }
+
+                // The icon data follows the read reply without a line ending
+                if mesg.message_type == "rd?" {
+                    let size = mesg.data.first().and_then(|size| size.parse().ok());
+                    if let Some(size) = size {
+                        let mut buffer = vec![0; size];
+                        if buf_reader.read_exact(&mut buffer).is_ok() {
+                            let _ = raw_sender.send(buffer);
+                        }
+                    }
                 }
-                handlers.clear();
             }
         });
 
@@ -146,50 +221,87 @@ impl MacroDeck {
             status: Arc::new(Mutex::new(None)),
             handlers: Arc::new(Mutex::new(HashMap::new())),
             status_handler: Arc::new(Mutex::new(None)),
+            raw_receiver: Mutex::new(raw_receiver),
+            exchange: Mutex::new(()),
+            next_request: AtomicUsize::new(0),
         })
     }
 
-    fn read(&self) -> Result<Message, &str> {
-        let mesg: Arc<(Mutex<Option<Message>>, Condvar)> =
-            Arc::new((Mutex::new(None), Condvar::new()));
+    /// Register for the next reply. This has to happen before the request is
+    /// written, otherwise a fast reply is lost.
+    fn expect_reply(&self) -> Result<(usize, Reply), &str> {
+        let mesg: Reply = Arc::new((Mutex::new(None), Condvar::new()));
 
-        {
-            let mut read_handler = self
-                .read_handler
-                .lock()
-                .map_err(|_| "Failed to lock read handler")?;
+        let mut read_handler = self
+            .read_handler
+            .lock()
+            .map_err(|_| "Failed to lock read handler")?;
 
-            let mesg_clone = mesg.clone();
+        let mesg_clone = mesg.clone();
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
 
-            read_handler.push(Box::new(move |rec_mesg| {
+        read_handler.push((
+            id,
+            Box::new(move |rec_mesg| {
                 let (lock, cvar) = &*mesg_clone;
                 let mut mesg = lock.lock().unwrap();
                 *mesg = Some(rec_mesg);
                 cvar.notify_all();
-            }));
+            }),
+        ));
+
+        Ok((id, mesg))
+    }
+
+    fn wait_reply(&self, (id, mesg): (usize, Reply)) -> Result<Message, &str> {
+        let reply = {
+            let (lock, cvar) = &*mesg;
+            let mesg = lock.lock().unwrap();
+            let mesg = cvar
+                .wait_timeout_while(mesg, Duration::from_secs(MAX_TIMEOUT), |mesg| {
+                    mesg.is_none()
+                })
+                .map_err(|_| "Failed to wait for message")?;
+            mesg.0.clone()
+        };
+
+        if reply.is_none() {
+            // Do not leave the handler behind for the reply to the next request
+            self.read_handler
+                .lock()
+                .map_err(|_| "Failed to lock read handler")?
+                .retain(|(handler_id, _)| *handler_id != id);
         }
 
-        let (lock, cvar) = &*mesg;
-        let mesg = lock.lock().unwrap();
-        let mesg = cvar
-            .wait_timeout(mesg, Duration::from_secs(MAX_TIMEOUT))
-            .map_err(|_| "Failed to wait for message")?;
+        reply.ok_or("Failed to read message")
+    }
 
-        mesg.0.clone().ok_or("Failed to read message")
+    /// Every exchange starts here, before any cache is locked, and keeps the
+    /// guard until its last reply.
+    fn exchange(&self) -> Result<MutexGuard<'_, ()>, &str> {
+        self.exchange.lock().map_err(|_| "Failed to lock exchange")
     }
 
-    // FIXME probably not working
-    fn read_exact(&self, buf: &mut [u8]) -> Result<(), &str> {
-        println!("Reading {} bytes", buf.len());
+    fn request(&self, message: &Message) -> Result<Message, &str> {
+        self.request_buffer(&message.encode())
+    }
 
-        let mut port = self.port.lock().map_err(|_| "Failed to lock port")?;
-        let mut buf_reader = BufReader::new(port.as_mut());
+    fn request_buffer(&self, buffer: &[u8]) -> Result<Message, &str> {
+        let reply = self.expect_reply()?;
+        self.write_buffer(buffer)?;
 
-        buf_reader
-            .read_exact(buf)
-            .map_err(|_| "Failed to read buffer")?;
+        self.wait_reply(reply)
+    }
 
-        Ok(())
+    fn read_raw(&self) -> Result<Vec<u8>, &str> {
+        let receiver = self
+            .raw_receiver
+            .lock()
+            .map_err(|_| "Failed to lock raw receiver")?;
+
+        receiver
+            .recv_timeout(Duration::from_secs(MAX_TIMEOUT))
+            .map_err(|_| "Failed to read buffer")
     }
 
     fn write(&self, message: &Message) -> Result<(), &str> {
@@ -210,14 +322,13 @@ impl MacroDeck {
     }
 
     pub fn get_info(&self) -> Result<DeviceInfo, &str> {
+        let _exchange = self.exchange()?;
         let mut info = self.info.lock().map_err(|_| "Failed to lock info")?;
-        if info.is_some() {
-            return Ok(info.clone().unwrap());
+        if let Some(info) = info.as_ref() {
+            return Ok(info.clone());
         }
 
-        self.write(&Message::new("li".to_string(), Vec::new()))?;
-
-        let message = self.read()?;
+        let message = self.request(&Message::new("li".to_string(), Vec::new()))?;
         if message.message_type != "li" {
             return Err("Failed to get device info");
         }
@@ -227,111 +338,126 @@ impl MacroDeck {
         let buttons_per_row = message.data[2].parse().unwrap();
         let num_of_rows = message.data[3].parse().unwrap();
         let gap_size = message.data[4].parse().unwrap();
-        let button_size = (width - (buttons_per_row - 1) * gap_size) / buttons_per_row;
-        let status_bar_height = height - num_of_rows * button_size - num_of_rows * gap_size;
 
-        let new_info = Some(DeviceInfo {
-            width,
-            height,
-            buttons_per_row,
-            num_of_rows,
-            gap_size,
-            button_size,
-            status_bar_height,
-        });
+        let new_info = DeviceInfo::new(width, height, buttons_per_row, num_of_rows, gap_size);
 
-        *info = new_info.clone();
+        *info = Some(new_info.clone());
 
-        Ok(new_info.unwrap())
+        Ok(new_info)
     }
 
     pub fn get_icon(&self, path: &str) -> Result<DynamicImage, &str> {
+        let _exchange = self.exchange()?;
         let mut icons = self.icons.lock().map_err(|_| "Failed to lock icons")?;
         if let Some(icon) = icons.get(path) {
             return Ok(icon.clone());
         }
 
-        self.write(&Message::new("ri".to_string(), vec![path.to_string()]))?;
+        let icon = decode_icon(self.read_file_locked(path)?)?;
+        icons.insert(path.to_string(), icon.clone());
+
+        Ok(icon)
+    }
+
+    /// Read the icon stored on the device, bypassing the cache.
+    pub fn fetch_icon(&self, path: &str) -> Result<DynamicImage, &str> {
+        decode_icon(self.read_file(path)?)
+    }
+
+    /// Read a file stored on the device, as the bytes it was written with.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, &str> {
+        let _exchange = self.exchange()?;
+        self.read_file_locked(path)
+    }
 
-        let message = self.read()?;
+    /// `read_file` for a caller holding the exchange.
+    fn read_file_locked(&self, path: &str) -> Result<Vec<u8>, &str> {
+        let message = self.request(&Message::new("ri".to_string(), vec![path.to_string()]))?;
         if message.message_type != "rd?" {
-            return Err("Failed to get icon");
+            return Err("Failed to read file");
         }
 
         let size = message.data[0]
-            .parse::<u32>()
+            .parse::<usize>()
             .map_err(|_| "Failed to parse size")?;
 
-        self.write(&Message::new("rd".to_string(), vec![]))?;
-
-        let mut buffer = vec![0; size as usize];
-        self.read_exact(&mut buffer)?;
+        // Drop the data of earlier reads that timed out
+        while self
+            .raw_receiver
+            .lock()
+            .map_err(|_| "Failed to lock raw receiver")?
+            .try_recv()
+            .is_ok()
+        {}
 
-        let icon = ImageReader::new(Cursor::new(buffer))
-            .with_guessed_format()
-            .map_err(|_| "Failed to decode icon")?
-            .decode()
-            .map_err(|_| "Failed to decode icon")?;
+        self.write(&Message::new("rd".to_string(), vec![]))?;
 
-        icons.insert(path.to_string(), icon.clone());
+        let buffer = self.read_raw()?;
+        if buffer.len() != size {
+            return Err("Failed to read buffer");
+        }
+        self.add_to_tree(path, NodeKind::File, Some(size as u64))?;
 
-        return Ok(icon);
+        Ok(buffer)
     }
 
-    fn add_path_to_dirs(&self, path: &str) -> Result<(), &str> {
+    /// Record a new node in the cached tree, if it was listed already.
+    fn add_to_tree(&self, path: &str, kind: NodeKind, size: Option<u64>) -> Result<(), &str> {
         let mut dirs = self.dirs.lock().map_err(|_| "Failed to lock dirs")?;
-        if dirs.is_some() {
-            let mut seen: HashSet<PathBuf> = dirs.as_mut().unwrap().iter().cloned().collect();
-            let mut current = Some(Path::new(path));
-            let mut to_add = vec![];
-
-            while let Some(p) = current {
-                current = p.parent();
-                if current.is_none() {
-                    break;
-                }
-
-                if !seen.contains(p) {
-                    to_add.push(p.to_path_buf());
-                    seen.insert(p.to_path_buf());
-                }
-            }
-
-            dirs.as_mut().unwrap().extend(to_add);
+        if let Some(tree) = dirs.as_mut() {
+            tree.insert(path, kind, size);
         }
 
         Ok(())
     }
 
-    pub fn set_icon(&self, icon_path: &str, icon: DynamicImage) -> Result<(), &str> {
+    /// Write an icon to the device as JPEG. Returns the size of the file.
+    pub fn set_icon(&self, icon_path: &str, icon: DynamicImage) -> Result<usize, &str> {
         // Convert the icon to JPEG format
         let mut buffer = Vec::new();
         icon.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Jpeg)
             .map_err(|_| "Failed to write icon")?;
 
+        let _exchange = self.exchange()?;
+        self.write_file_locked(icon_path, &buffer)?;
+
         // Update cache
         let mut icons = self.icons.lock().map_err(|_| "Failed to lock icons")?;
         icons.insert(icon_path.to_string(), icon);
 
-        self.write(&Message::new(
+        Ok(buffer.len())
+    }
+
+    /// Write `data` to a file on the device as is. Missing parent folders are
+    /// created by the firmware.
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<(), &str> {
+        let _exchange = self.exchange()?;
+        self.write_file_locked(path, data)
+    }
+
+    /// `write_file` for a caller holding the exchange.
+    fn write_file_locked(&self, path: &str, data: &[u8]) -> Result<(), &str> {
+        // The cached icon is outdated whatever happens next
+        self.icons
+            .lock()
+            .map_err(|_| "Failed to lock icons")?
+            .remove(path);
+
+        let message = self.request(&Message::new(
             "wi".to_string(),
-            vec![icon_path.to_string(), buffer.len().to_string()],
+            vec![path.to_string(), data.len().to_string()],
         ))?;
-
-        let message = self.read()?;
         if message.message_type != "rd" {
-            return Err("Failed to write icon");
+            return Err("Failed to write file");
         }
 
-        self.write_buffer(&buffer)?;
-
-        let message = self.read()?;
+        let message = self.request_buffer(data)?;
         if message.message_type != "ok" {
-            return Err("Failed to write icon");
+            return Err("Failed to write file");
         }
 
         // Update dirs
-        self.add_path_to_dirs(icon_path)?;
+        self.add_to_tree(path, NodeKind::File, Some(data.len() as u64))?;
 
         Ok(())
     }
@@ -341,6 +467,7 @@ impl MacroDeck {
             return Err("Status image size does not match");
         }
 
+        let _exchange = self.exchange()?;
         let mut old_status = self.status.lock().map_err(|_| "Failed to lock status")?;
         let (x, y, patch) = if let Some(old_status) = old_status.as_ref() {
             if let Some(result) = find_patch(old_status, &status) {
@@ -357,19 +484,15 @@ impl MacroDeck {
             .write_to(&mut Cursor::new(&mut buffer), ImageFormat::Jpeg)
             .map_err(|_| "Failed to write image")?;
 
-        self.write(&Message::new(
+        let message = self.request(&Message::new(
             "ss".to_string(),
             vec![x.to_string(), y.to_string(), buffer.len().to_string()],
         ))?;
-
-        let message = self.read()?;
         if message.message_type != "rd" {
             return Err("Failed to set status");
         }
 
-        self.write_buffer(&buffer)?;
-
-        let message = self.read()?;
+        let message = self.request_buffer(&buffer)?;
         if message.message_type != "ok" {
             return Err("Failed to set status");
         }
@@ -388,23 +511,27 @@ impl MacroDeck {
     }
 
     pub fn list_directory(&self) -> Result<Vec<PathBuf>, &str> {
+        Ok(self.get_tree()?.paths())
+    }
+
+    /// The folders and files on the device, listed once and then kept up to
+    /// date by the other calls.
+    pub fn get_tree(&self) -> Result<DeviceTree, &str> {
+        let _exchange = self.exchange()?;
         let mut dirs = self.dirs.lock().map_err(|_| "Failed to lock dirs")?;
-        if dirs.is_some() {
-            return Ok(dirs.clone().unwrap());
+        if let Some(tree) = dirs.as_ref() {
+            return Ok(tree.clone());
         }
 
-        self.write(&Message::new("ld".to_string(), Vec::new()))?;
-
-        let message = self.read()?;
+        let message = self.request(&Message::new("ld".to_string(), Vec::new()))?;
         if message.message_type != "ld" {
             return Err("Failed to list directory");
         }
 
-        let new_dirs: Vec<PathBuf> = message.data.iter().map(|str| PathBuf::from(str)).collect();
-
-        *dirs = Some(new_dirs.clone());
+        let tree = DeviceTree::from_paths(&message.data);
+        *dirs = Some(tree.clone());
 
-        Ok(new_dirs)
+        Ok(tree)
     }
 
     pub fn set_profile(&self, profile_name: &str) -> Result<(), &str> {
@@ -415,18 +542,16 @@ impl MacroDeck {
         let result = send_and_check_ok!(self, "cf", path, "Failed to create folder");
 
         if result.is_ok() {
-            self.add_path_to_dirs(path)?;
+            self.add_to_tree(path, NodeKind::Folder, None)?;
         }
 
         result
     }
 
-    fn remove_path_from_dirs(&self, path: &str) -> Result<(), &str> {
+    fn remove_from_tree(&self, path: &str) -> Result<(), &str> {
         let mut dirs = self.dirs.lock().map_err(|_| "Failed to lock dirs")?;
-        if dirs.is_some() {
-            dirs.as_mut()
-                .unwrap()
-                .retain(|p| !p.starts_with(&Path::new(path)));
+        if let Some(tree) = dirs.as_mut() {
+            tree.remove(path);
         }
 
         Ok(())
@@ -436,7 +561,7 @@ impl MacroDeck {
         let result = send_and_check_ok!(self, "di", icon_path, "Failed to remove icon");
 
         if result.is_ok() {
-            self.remove_path_from_dirs(icon_path)?;
+            self.remove_from_tree(icon_path)?;
         }
 
         result
@@ -446,7 +571,7 @@ impl MacroDeck {
         let result = send_and_check_ok!(self, "df", folder_path, "Failed to remove folder");
 
         if result.is_ok() {
-            self.remove_path_from_dirs(folder_path)?;
+            self.remove_from_tree(folder_path)?;
         }
 
         result
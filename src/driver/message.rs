@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Clone, Debug)]
 pub struct Message {
     pub message_type: String,
@@ -9,15 +11,6 @@ impl Message {
         Self { message_type, data }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{}{}{}",
-            self.message_type.len(),
-            self.message_type,
-            self.data.join(" ")
-        )
-    }
-
     pub fn encode(&self) -> Vec<u8> {
         self.to_string().as_bytes().to_vec()
     }
@@ -35,3 +28,15 @@ impl Message {
         Some(Self::new(message_type, data))
     }
 }
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.message_type.len(),
+            self.message_type,
+            self.data.join(" ")
+        )
+    }
+}
@@ -1,2 +1,3 @@
 pub mod macro_deck;
 pub mod message;
+pub mod tree;
@@ -0,0 +1,308 @@
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Folder,
+    File,
+}
+
+impl NodeKind {
+    /// The firmware only stores images, so anything with an extension is a file.
+    pub fn guess(path: &str) -> Self {
+        if Path::new(path).extension().is_some() {
+            NodeKind::File
+        } else {
+            NodeKind::Folder
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TreeNode {
+    pub kind: NodeKind,
+    /// Size in bytes of a file, if known. `ld` does not report sizes, they are
+    /// recorded when a file is written or read.
+    pub size: Option<u64>,
+    pub children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    fn new(kind: NodeKind, size: Option<u64>) -> Self {
+        TreeNode {
+            kind,
+            size,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+/// Differences between the device and a desired layout
+#[derive(Clone, Debug, Default)]
+pub struct TreeDiff {
+    /// Paths missing from the device, parents first
+    pub added: Vec<String>,
+    /// Paths only on the device. Children of removed folders are left out.
+    pub removed: Vec<String>,
+    /// Files on both sides whose known sizes differ
+    pub changed: Vec<String>,
+}
+
+/// The folders and files stored on the device
+#[derive(Clone, Debug)]
+pub struct DeviceTree {
+    root: TreeNode,
+}
+
+impl Default for DeviceTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn components(path: &str) -> Vec<String> {
+    Path::new(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn join(parent: &str, name: &str) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name)
+}
+
+fn walk_node<'a>(path: &str, node: &'a TreeNode, nodes: &mut Vec<(String, &'a TreeNode)>) {
+    for (name, child) in &node.children {
+        let child_path = join(path, name);
+        nodes.push((child_path.clone(), child));
+        walk_node(&child_path, child, nodes);
+    }
+}
+
+impl DeviceTree {
+    pub fn new() -> Self {
+        DeviceTree {
+            root: TreeNode::new(NodeKind::Folder, None),
+        }
+    }
+
+    /// Build the tree from the paths reported by `ld`.
+    pub fn from_paths<S: AsRef<str>>(paths: &[S]) -> Self {
+        let mut tree = DeviceTree::new();
+        for path in paths {
+            let path = path.as_ref();
+            tree.insert(path, NodeKind::guess(path), None);
+        }
+
+        tree
+    }
+
+    pub fn root(&self) -> &TreeNode {
+        &self.root
+    }
+
+    pub fn get(&self, path: &str) -> Option<&TreeNode> {
+        components(path)
+            .iter()
+            .try_fold(&self.root, |node, name| node.children.get(name))
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// Add a node, creating its missing parents as folders. Updates the size
+    /// of an existing node when one is given.
+    pub fn insert(&mut self, path: &str, kind: NodeKind, size: Option<u64>) {
+        let names = components(path);
+        let (last, parents) = match names.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+
+        let mut node = &mut self.root;
+        for name in parents {
+            node = node
+                .children
+                .entry(name.clone())
+                .or_insert_with(|| TreeNode::new(NodeKind::Folder, None));
+        }
+
+        let node = node
+            .children
+            .entry(last.clone())
+            .or_insert_with(|| TreeNode::new(kind, size));
+        node.kind = kind;
+        if size.is_some() {
+            node.size = size;
+        }
+    }
+
+    /// Remove a node and everything below it.
+    pub fn remove(&mut self, path: &str) -> Option<TreeNode> {
+        let names = components(path);
+        let (last, parents) = match names.split_last() {
+            Some(split) => split,
+            // Removing the root empties the tree
+            None => return Some(std::mem::replace(&mut self.root, DeviceTree::new().root)),
+        };
+
+        let mut node = &mut self.root;
+        for name in parents {
+            node = node.children.get_mut(name)?;
+        }
+
+        node.children.remove(last)
+    }
+
+    /// Every node except the root with its path, parents before their children.
+    pub fn walk(&self) -> Vec<(String, &TreeNode)> {
+        let mut nodes = vec![];
+        walk_node("/", &self.root, &mut nodes);
+        nodes
+    }
+
+    /// Every path, as reported by `ld`.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.walk()
+            .into_iter()
+            .map(|(path, _)| PathBuf::from(path))
+            .collect()
+    }
+
+    /// What has to change to turn this tree into `desired`.
+    pub fn diff(&self, desired: &DeviceTree) -> TreeDiff {
+        fn visit(path: &str, current: &TreeNode, desired: &TreeNode, diff: &mut TreeDiff) {
+            for (name, node) in &current.children {
+                let child_path = join(path, name);
+                match desired.children.get(name) {
+                    Some(other) if other.kind == node.kind => {
+                        if node.kind == NodeKind::File
+                            && node.size.is_some()
+                            && other.size.is_some()
+                            && node.size != other.size
+                        {
+                            diff.changed.push(child_path.clone());
+                        }
+                        visit(&child_path, node, other, diff);
+                    }
+                    _ => diff.removed.push(child_path),
+                }
+            }
+
+            for (name, node) in &desired.children {
+                let child_path = join(path, name);
+                match current.children.get(name) {
+                    Some(other) if other.kind == node.kind => {}
+                    _ => {
+                        diff.added.push(child_path.clone());
+
+                        let mut nodes = vec![];
+                        walk_node(&child_path, node, &mut nodes);
+                        diff.added.extend(nodes.into_iter().map(|(path, _)| path));
+                    }
+                }
+            }
+        }
+
+        let mut diff = TreeDiff::default();
+        visit("/", &self.root, &desired.root, &mut diff);
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: &[(&str, Option<u64>)]) -> DeviceTree {
+        let mut tree = DeviceTree::new();
+        for &(path, size) in files {
+            tree.insert(path, NodeKind::guess(path), size);
+        }
+        tree
+    }
+
+    #[test]
+    fn diff_of_equal_trees_is_empty() {
+        let current = tree(&[("/default/aio.jpg", Some(10)), ("/default/0", None)]);
+        let desired = tree(&[("/default/aio.jpg", Some(10)), ("/default/0", None)]);
+
+        let diff = current.diff(&desired);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn diff_adds_parents_first() {
+        let current = tree(&[("/default/aio.jpg", None)]);
+        let desired = tree(&[
+            ("/default/aio.jpg", None),
+            ("/default/0/1/aio.jpg", None),
+            ("/work/aio.jpg", None),
+        ]);
+
+        assert_eq!(
+            current.diff(&desired).added,
+            [
+                "/default/0",
+                "/default/0/1",
+                "/default/0/1/aio.jpg",
+                "/work",
+                "/work/aio.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_removes_folders_without_their_children() {
+        let current = tree(&[
+            ("/default/aio.jpg", None),
+            ("/default/0/aio.jpg", None),
+            ("/default/0/1/aio.jpg", None),
+            ("/default/1.jpg", None),
+        ]);
+        let desired = tree(&[("/default/aio.jpg", None)]);
+
+        assert_eq!(
+            current.diff(&desired).removed,
+            ["/default/0", "/default/1.jpg"]
+        );
+    }
+
+    #[test]
+    fn diff_replaces_nodes_of_another_kind() {
+        let mut current = DeviceTree::new();
+        current.insert("/default/0", NodeKind::File, None);
+        let desired = tree(&[("/default/0/aio.jpg", None)]);
+
+        let diff = current.diff(&desired);
+        assert_eq!(diff.removed, ["/default/0"]);
+        assert_eq!(diff.added, ["/default/0", "/default/0/aio.jpg"]);
+    }
+
+    #[test]
+    fn diff_changes_files_of_another_known_size() {
+        let current = tree(&[
+            ("/default/aio.jpg", Some(10)),
+            ("/work/aio.jpg", Some(10)),
+            ("/home/aio.jpg", None),
+        ]);
+        let desired = tree(&[
+            ("/default/aio.jpg", Some(12)),
+            ("/work/aio.jpg", None),
+            ("/home/aio.jpg", Some(12)),
+        ]);
+
+        assert_eq!(current.diff(&desired).changed, ["/default/aio.jpg"]);
+    }
+}
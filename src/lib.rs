@@ -1,4 +1,5 @@
 mod driver;
 
-pub use driver::macro_deck::MacroDeck;
+pub use driver::macro_deck::{DeviceInfo, MacroDeck};
 pub use driver::message::Message;
+pub use driver::tree::{DeviceTree, NodeKind, TreeDiff, TreeNode};
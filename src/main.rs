@@ -1,7 +1,16 @@
 mod cli;
 
 use cli::{
-    background_start::background_start, flash::flash, list::list, start::start, stop::stop,
+    background_start::background_start,
+    backup::{backup, restore},
+    flash::flash,
+    fs,
+    list::list,
+    models::{Fit, FlashOptions, IconLayout},
+    profile,
+    screenshot::screenshot,
+    start::start,
+    stop::stop,
     tools::write_icons_to_config,
 };
 
@@ -38,8 +47,128 @@ enum Commands {
     Flash {
         #[arg(short, long)]
         tcp_port: Option<String>,
-        #[arg(short, long)]
+        #[arg(short, long, visible_alias = "config")]
         config_path: Option<String>,
+        #[arg(
+            short,
+            long,
+            conflicts_with_all = ["tcp_port", "dry_run"],
+            help = "Flash the device on this serial port directly, without the driver"
+        )]
+        port: Option<String>,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Upload every folder, even the unchanged ones"
+        )]
+        full: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Read the uploaded images back and upload mismatches again"
+        )]
+        verify: bool,
+        #[arg(
+            long,
+            value_enum,
+            help = "How the images are stored on the device, overrides the config"
+        )]
+        layout: Option<IconLayout>,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Render the images to disk instead of flashing the device"
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            requires = "dry_run",
+            help = "Output directory of the dry run [default: flash-preview]"
+        )]
+        out: Option<String>,
+        #[arg(
+            long,
+            requires = "dry_run",
+            help = "Device geometry as WxH,cols,rows,gap for offline dry runs"
+        )]
+        device_info: Option<String>,
+    },
+    #[command(about = "Save what the device displays as a PNG")]
+    Screenshot {
+        #[arg(short, long)]
+        tcp_port: Option<String>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "tcp_port",
+            help = "Read the device on this serial port directly, without the driver"
+        )]
+        port: Option<String>,
+        #[arg(
+            short,
+            long,
+            help = "Folder shown on the device [default: the active profile]"
+        )]
+        folder: Option<String>,
+        #[arg(short, long, help = "Output file [default: screenshot.png]")]
+        out: Option<String>,
+    },
+    #[command(about = "Save every file on the device to a tar archive")]
+    Backup {
+        #[arg(short, long)]
+        tcp_port: Option<String>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "tcp_port",
+            help = "Use the device on this serial port directly, without the driver"
+        )]
+        port: Option<String>,
+        #[arg(short, long, help = "Output archive [default: deck.tar]")]
+        out: Option<String>,
+    },
+    #[command(about = "Replace everything on the device with a backup")]
+    Restore {
+        archive: String,
+        #[arg(short, long)]
+        tcp_port: Option<String>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "tcp_port",
+            help = "Use the device on this serial port directly, without the driver"
+        )]
+        port: Option<String>,
+    },
+    #[command(about = "Inspect and edit the files on the device")]
+    Fs {
+        #[arg(short, long, global = true)]
+        tcp_port: Option<String>,
+        #[arg(
+            short,
+            long,
+            global = true,
+            conflicts_with = "tcp_port",
+            help = "Use the device on this serial port directly, without the driver"
+        )]
+        port: Option<String>,
+        #[command(subcommand)]
+        command: FsCommand,
+    },
+    #[command(about = "Switch between the profiles of the device")]
+    Profile {
+        #[arg(short, long, global = true)]
+        tcp_port: Option<String>,
+        #[arg(
+            short,
+            long,
+            global = true,
+            conflicts_with = "tcp_port",
+            help = "Use the device on this serial port directly, without the driver"
+        )]
+        port: Option<String>,
+        #[command(subcommand)]
+        command: ProfileCommand,
     },
     #[command(about = "Tools for various tasks")]
     Tools {
@@ -56,9 +185,49 @@ enum Tools {
         icons_dir: String,
         #[arg(short, long)]
         config_path: Option<String>,
+        #[arg(long, value_enum, help = "How the icons are scaled into the buttons")]
+        fit: Option<Fit>,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Reference the icon files by path instead of embedding them"
+        )]
+        link: bool,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum FsCommand {
+    #[command(about = "List a folder [default: /]")]
+    Ls {
+        path: Option<String>,
+        #[arg(long, default_value_t = false, help = "List the subfolders too")]
+        tree: bool,
+    },
+    #[command(about = "Create a folder")]
+    Mkdir { path: String },
+    #[command(about = "Remove a file or a folder")]
+    Rm {
+        path: String,
+        #[arg(short, long, default_value_t = false)]
+        recursive: bool,
+    },
+    #[command(
+        about = "Upload an image, converted to JPEG if needed, to a .jpg path, the aio.jpg of a folder or the <path>.jpg of a button"
+    )]
+    Push { local: String, remote: String },
+    #[command(about = "Download a file as stored on the device")]
+    Pull { remote: String, local: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommand {
+    #[command(about = "Show a profile on the device, overriding the rules")]
+    Switch { name: String },
+    #[command(about = "Clear the override and let the rules pick the profile")]
+    Auto,
+}
+
 fn main() {
     env_logger::init();
 
@@ -82,12 +251,67 @@ fn main() {
         Commands::Flash {
             tcp_port,
             config_path,
-        } => flash(tcp_port, config_path),
+            port,
+            full,
+            verify,
+            layout,
+            dry_run,
+            out,
+            device_info,
+        } => flash(
+            tcp_port,
+            config_path,
+            port,
+            dry_run.then(|| out.unwrap_or("flash-preview".to_string())),
+            device_info,
+            FlashOptions {
+                full,
+                verify,
+                layout,
+            },
+        ),
+        Commands::Screenshot {
+            tcp_port,
+            port,
+            folder,
+            out,
+        } => screenshot(tcp_port, port, folder, out),
+        Commands::Backup {
+            tcp_port,
+            port,
+            out,
+        } => backup(tcp_port, port, out),
+        Commands::Restore {
+            archive,
+            tcp_port,
+            port,
+        } => restore(tcp_port, port, archive),
+        Commands::Fs {
+            tcp_port,
+            port,
+            command,
+        } => match command {
+            FsCommand::Ls { path, tree } => fs::ls(tcp_port, port, path, tree),
+            FsCommand::Mkdir { path } => fs::mkdir(tcp_port, port, path),
+            FsCommand::Rm { path, recursive } => fs::rm(tcp_port, port, path, recursive),
+            FsCommand::Push { local, remote } => fs::push(tcp_port, port, local, remote),
+            FsCommand::Pull { remote, local } => fs::pull(tcp_port, port, remote, local),
+        },
+        Commands::Profile {
+            tcp_port,
+            port,
+            command,
+        } => match command {
+            ProfileCommand::Switch { name } => profile::switch(tcp_port, port, name),
+            ProfileCommand::Auto => profile::auto(tcp_port, port),
+        },
         Commands::Tools { tool } => match tool {
             Tools::WriteIconsToConfig {
                 icons_dir,
                 config_path,
-            } => write_icons_to_config(icons_dir, config_path),
+                fit,
+                link,
+            } => write_icons_to_config(icons_dir, config_path, fit, link),
         },
     }
 }